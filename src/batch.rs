@@ -0,0 +1,140 @@
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::Result;
+use glob::Pattern;
+use crate::image::Format;
+use crate::util::is_pattern;
+
+
+/// A file to convert, or a pattern to draw.
+pub struct Input {
+    pub path: String,
+    /// Where the input sits below the directory or glob it was found through, so the output can
+    /// mirror it. Empty for inputs that were given directly.
+    pub relative_dir: PathBuf,
+    /// Whether the input was found through a directory or glob, rather than given directly.
+    pub expanded: bool,
+}
+
+
+impl Input {
+    pub fn new(path: &Path, root: &Path) -> Self {
+        let relative_dir = path.parent()
+            .and_then(|parent| parent.strip_prefix(root).ok())
+            .map(PathBuf::from)
+            .unwrap_or_default();
+        Self { path: path.to_string_lossy().into_owned(), relative_dir, expanded: true }
+    }
+
+    /// Files in directories that imcon can't read are skipped. Inputs given directly are always
+    /// tried, so they fail if they can't be read.
+    pub fn is_supported(&self) -> bool {
+        !self.expanded || is_supported(Path::new(&self.path))
+    }
+}
+
+
+/// Which of the files found in directories and globs to convert, by their name.
+#[derive(Default)]
+pub struct Filter {
+    include: Vec<Pattern>,
+    exclude: Vec<Pattern>,
+}
+
+
+impl Filter {
+    pub fn new<'a>(include: impl IntoIterator<Item=&'a str>, exclude: impl IntoIterator<Item=&'a str>) -> Result<Self> {
+        Ok(Self {
+            include: include.into_iter().map(Pattern::new).collect::<Result<_, _>>()?,
+            exclude: exclude.into_iter().map(Pattern::new).collect::<Result<_, _>>()?,
+        })
+    }
+
+    /// Inputs given directly are always accepted.
+    pub fn accepts(&self, input: &Input) -> bool {
+        let name = Path::new(&input.path).file_name().unwrap_or_default().to_string_lossy();
+        !input.expanded || (
+            (self.include.is_empty() || self.include.iter().any(|p| p.matches(&name)))
+                && !self.exclude.iter().any(|p| p.matches(&name))
+        )
+    }
+}
+
+
+fn is_supported(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| Format::from_str(&ext.to_string_lossy()).is_ok())
+}
+
+
+fn has_wildcards(input: &str) -> bool {
+    input.contains(['*', '?', '['])
+}
+
+
+/// The leading components of a glob that don't contain wildcards.
+fn glob_root(pattern: &str) -> PathBuf {
+    Path::new(pattern).components()
+        .take_while(|c| !matches!(c, Component::Normal(name) if has_wildcards(&name.to_string_lossy())))
+        .collect()
+}
+
+
+/// The files in `dir`, sorted, descending into subdirectories if `recursive` is set.
+fn walk(dir: &Path, recursive: bool, files: &mut Vec<PathBuf>) -> Result<()> {
+    let mut entries = fs::read_dir(dir)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    entries.sort();
+    for path in entries {
+        if path.is_dir() {
+            if recursive {
+                walk(&path, recursive, files)?;
+            }
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+
+/// Expand directories and globs among `inputs` into the files they contain. Files and patterns
+/// are kept as given, in order.
+pub fn collect_inputs<'a>(inputs: impl IntoIterator<Item=&'a str>, recursive: bool) -> Result<Vec<Input>> {
+    let mut collected = Vec::new();
+    for input in inputs {
+        let path = Path::new(input);
+        if path.is_dir() {
+            let mut files = Vec::new();
+            walk(path, recursive, &mut files)?;
+            collected.extend(files.iter().map(|file| Input::new(file, path)));
+        } else if !path.exists() && !is_pattern(input) && has_wildcards(input) {
+            let root = glob_root(input);
+            for file in glob::glob(input)? {
+                let file = file?;
+                if file.is_file() {
+                    collected.push(Input::new(&file, &root));
+                }
+            }
+        } else {
+            collected.push(Input { path: input.to_string(), relative_dir: PathBuf::new(), expanded: false });
+        }
+    }
+    Ok(collected)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mirrors_directories_below_the_root() {
+        assert_eq!(glob_root("photos/**/*.jpg"), PathBuf::from("photos"));
+        let input = Input::new(Path::new("photos/2021/june/a.jpg"), Path::new("photos"));
+        assert_eq!(input.relative_dir, PathBuf::from("2021/june"));
+        assert_eq!(Input::new(Path::new("photos/a.jpg"), Path::new("photos")).relative_dir, PathBuf::new());
+    }
+}
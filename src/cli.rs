@@ -1,78 +1,266 @@
 use clap::{AppSettings, Arg};
 use crate::{NAME, VERSION};
 
+
+/// Options that may be given separately for each output, after its `-o`. They may be repeated, and
+/// pairs of them that can't be used together are checked once they're assigned to outputs.
+pub const OUTPUT_OPTIONS: &[&str] = &[
+    "output", "output-format", "scale", "width", "height", "max-width", "max-height", "colors",
+    "palette-file", "dither", "strip", "keep", "strip-gps",
+];
+
 pub fn clap_app() -> clap::App<'static> {
     clap::App::new(NAME)
         .version(VERSION)
         .setting(AppSettings::ArgRequiredElseHelp)
+        .setting(AppSettings::SubcommandsNegateReqs)
         .arg(Arg::new("input")
-            .help("Sets the input file to use")
+            .help("Sets the input files, directories or globs to use, or a color or pattern to draw, e.g. '#ff000080', 'linear-gradient(red, blue)', 'checkerboard(16)' or 'noise(42)'")
             .required(true)
             .multiple_values(true)
         )
-        .arg(Arg::new("output-format")
+        .args(pipeline_args())
+        .subcommand(clap::App::new("watch")
+            .about("Watch a directory, and convert images as they are added or modified, until interrupted.")
+            .arg(Arg::new("input")
+                .help("Sets the directory to watch")
+                .required(true)
+            )
+            .arg(Arg::new("poll")
+                .long("poll")
+                .help("Check the directory for changes periodically, instead of relying on filesystem notifications. Use this for network drives. imcon also falls back to polling when notifications are unavailable.")
+            )
+            .arg(Arg::new("interval")
+                .long("interval")
+                .takes_value(true)
+                .value_name("MS")
+                .default_value("1000")
+                .help("How often to check for changes when polling.")
+            )
+            .arg(Arg::new("debounce")
+                .long("debounce")
+                .takes_value(true)
+                .value_name("MS")
+                .default_value("500")
+                .help("Wait until a file hasn't changed for this long before converting it, so files still being written are converted once.")
+            )
+            .args(pipeline_args())
+        )
+}
+
+
+/// The options of the conversion pipeline, shared by the main command and `watch`.
+fn pipeline_args() -> Vec<Arg<'static>> {
+    vec![
+        Arg::new("output-format")
             .long("output-format")
+            .multiple_occurrences(true)
             .help("Sets the output format")
             .takes_value(true)
-            .conflicts_with("output")
-            .possible_values(&["png", "jpg", "jpeg"])
-        )
-        .arg(Arg::new("input-format")
+            .possible_values(&["png", "jpg", "jpeg", "tiff", "webp"]),
+        Arg::new("input-format")
             .long("input-format")
-            .takes_value(true)
-        )
-        .arg(Arg::new("scale")
+            .takes_value(true),
+        Arg::new("scale")
             .long("scale")
-            .takes_value(true)
-        )
-        .arg(Arg::new("width")
+            .multiple_occurrences(true)
+            .takes_value(true),
+        Arg::new("width")
             .long("width")
+            .multiple_occurrences(true)
             .short('w')
-            .takes_value(true)
-        )
-        .arg(Arg::new("height")
+            .takes_value(true),
+        Arg::new("height")
             .long("height")
+            .multiple_occurrences(true)
             .short('h')
-            .takes_value(true)
-        )
-        .arg(Arg::new("max-width")
+            .takes_value(true),
+        Arg::new("max-width")
             .long("max-width")
+            .multiple_occurrences(true)
             .short('W')
-            .takes_value(true)
-        )
-        .arg(Arg::new("max-height")
+            .takes_value(true),
+        Arg::new("max-height")
             .long("max-height")
+            .multiple_occurrences(true)
             .short('H')
-            .takes_value(true)
-        )
-        .arg(Arg::new("metadata")
+            .takes_value(true),
+        Arg::new("metadata")
             .long("metadata")
             .short('m')
+            .help("Print the image's metadata instead of converting it.")
             .multiple_values(true)
             .multiple_occurrences(true)
+            .min_values(0)
             .conflicts_with_all(&["width", "height", "max-width", "max-height", "scale", "output-format", "output"])
-            .possible_values(&["all"])
-        )
-        // .arg(Arg::new("dominant")
-        //     .long("dominant")
-        //     .takes_value(true)
-        //     .conflicts_with_all(&["width", "height", "max-width", "max-height", "scale", "output-format", "output"])
-        // )
-        .arg(Arg::new("output")
+            .possible_values(&["all"]),
+        Arg::new("dominant")
+            .long("dominant")
+            .takes_value(true)
+            .value_name("N")
+            .help("Print the N dominant colors of the image and their share of its pixels. With -o, write them as a swatch image instead, sized by -w and -h.")
+            .conflicts_with_all(&["metadata", "max-width", "max-height", "scale", "output-format"]),
+        Arg::new("palette-algorithm")
+            .long("palette-algorithm")
+            .takes_value(true)
+            .possible_values(&["kmeans", "median-cut"])
+            .default_value("kmeans")
+            .requires("dominant")
+            .help("How to find the dominant colors. median-cut is faster and always gives the same result."),
+        Arg::new("palette-format")
+            .long("palette-format")
+            .takes_value(true)
+            .possible_values(&["hex", "json"])
+            .default_value("hex")
+            .requires("dominant")
+            .help("How to print the dominant colors."),
+        Arg::new("colors")
+            .long("colors")
+            .multiple_occurrences(true)
+            .takes_value(true)
+            .value_name("N")
+            .help("Reduce the image to its N best colors, up to 256. The PNG is then written with a palette."),
+        Arg::new("palette-file")
+            .long("palette-file")
+            .multiple_occurrences(true)
+            .takes_value(true)
+            .value_name("FILE")
+            .help("Reduce the image to the colors in this file: one CSS color or 'R G B' per line, as in GIMP palettes."),
+        Arg::new("dither")
+            .long("dither")
+            .multiple_occurrences(true)
+            .takes_value(true)
+            .possible_values(&["none", "floyd-steinberg", "ordered"])
+            .default_value("floyd-steinberg")
+            .help("How to dither when reducing colors with --colors or --palette-file."),
+        Arg::new("output")
             .short('o')
             .long("output")
+            .multiple_occurrences(true)
             .help("Sets the output file path to use. Use the following placeholders as needed:
               '{}':   input file name without file extension
               '{i}':  number of the output file (starting from 1).
               '{dir}':  input file dir
               '{filename}':  input file name with file extension
+              '{ext}':  input file extension
+              '{format}':  output format: the path's extension, or if that is '{format}', the input's
+                           default output format, e.g. jpg for HEIC
+              '{width}', '{height}':  output image dimensions
+              '{page_count}':  number of pages or images in the input
+              '{hash}':  hash of the output file's contents
+              '{date}':  EXIF capture date, in the camera's local time, or else the input's modification
+                         time in UTC, as YYYY-MM-DD.
+                         Use '{date:%Y/%m}' for other strftime formats (%Y %y %m %d %H %M %S %F %T).
+Repeat -o to write several outputs, decoding each input once. Resize, color, format and metadata
+options after an -o only apply to that output, e.g. -o '{}@1x.jpg' -w 400 -o '{}@2x.webp' -w 800
             ")
+            .takes_value(true),
+        Arg::new("out-dir")
+            .long("out-dir")
             .takes_value(true)
-            .conflicts_with("output-format")
-        )
-        .arg(Arg::new("force")
+            .value_name("DIR")
+            .help("Write outputs into this directory, creating it if needed. Files found in an input directory or glob keep their relative path below it."),
+        Arg::new("relative-to-input")
+            .long("relative-to-input")
+            .help("Resolve the output path, and --out-dir, relative to each input file's directory instead of the current directory."),
+        Arg::new("include")
+            .long("include")
+            .takes_value(true)
+            .value_name("PATTERN")
+            .multiple_occurrences(true)
+            .use_delimiter(true)
+            .help("Only convert the files in input directories and globs whose name matches one of these patterns, e.g. --include '*.jpg,*.png'"),
+        Arg::new("exclude")
+            .long("exclude")
+            .takes_value(true)
+            .value_name("PATTERN")
+            .multiple_occurrences(true)
+            .use_delimiter(true)
+            .help("Don't convert the files in input directories and globs whose name matches one of these patterns."),
+        Arg::new("recursive")
+            .short('r')
+            .long("recursive")
+            .help("Convert the images in subdirectories of input directories too."),
+        Arg::new("jobs")
+            .short('j')
+            .long("jobs")
+            .takes_value(true)
+            .help("How many images to convert at once. Defaults to the number of CPUs."),
+        Arg::new("keep-going")
+            .long("keep-going")
+            .short('k')
+            .help("Report inputs that fail and carry on with the others, then print a summary. Exits with 3 if some inputs failed, or 1 if all of them did."),
+        Arg::new("skip-existing")
+            .long("skip-existing")
+            .help("Skip inputs whose outputs, e.g. every page of a PDF, already exist. Outputs whose path depends on the converted image, like '{hash}', are always written."),
+        Arg::new("newer-only")
+            .long("newer-only")
+            .conflicts_with("skip-existing")
+            .help("Skip inputs whose outputs exist and were modified after the input."),
+        Arg::new("manifest")
+            .long("manifest")
+            .takes_value(true)
+            .value_name("FILE")
+            .help("Record the hash of each input and of the options, including files like --palette-file, and the files it was converted to, in this file. Inputs whose contents and options haven't changed since, and whose outputs still exist, are skipped."),
+        Arg::new("heif-images")
+            .long("heif-images")
+            .help("Which images to decode from a HEIF file. Anything other than 'primary' writes one file per image.")
+            .takes_value(true)
+            .possible_values(&["primary", "all", "thumbnails", "depth"])
+            .default_value("primary"),
+        Arg::new("no-auto-orient")
+            .long("no-auto-orient")
+            .help("Don't rotate images upright according to their EXIF orientation, or a HEIF file's rotation and mirroring."),
+        Arg::new("strip")
+            .long("strip")
+            .multiple_occurrences(true)
+            .help("Don't copy metadata (EXIF, XMP, IPTC, ICC) from the input into the output."),
+        Arg::new("keep")
+            .long("keep")
+            .multiple_occurrences(true)
+            .help("Only copy these kinds of metadata into the output, e.g. --keep exif,icc")
+            .takes_value(true)
+            .multiple_values(true)
+            .use_delimiter(true)
+            .possible_values(&["exif", "xmp", "iptc", "icc"]),
+        Arg::new("strip-gps")
+            .long("strip-gps")
+            .multiple_occurrences(true)
+            .help("Remove GPS location data from the EXIF and XMP metadata copied into the output."),
+        Arg::new("convert-profile")
+            .long("convert-profile")
+            .help("Convert colors from the input's ICC profile to 'srgb' or the ICC profile file at this path, and embed that profile in the output.")
+            .takes_value(true),
+        Arg::new("pdfium-lib")
+            .long("pdfium-lib")
+            .help("Path to the pdfium library, or a directory containing it. Overrides the IMCON_PDFIUM_PATH environment variable.")
+            .takes_value(true),
+        Arg::new("in-place")
+            .long("in-place")
+            .short('i')
+            .help("Replace each input file with the converted image, in the same format. The file is replaced atomically, keeping its permissions and modification time. Fails for PDF and HEIF files, TIFFs with several pages and animated GIFs.")
+            .conflicts_with_all(&["output", "output-format", "out-dir", "relative-to-input", "metadata", "dominant", "skip-existing", "newer-only", "manifest"]),
+        Arg::new("backup")
+            .long("backup")
+            .requires("in-place")
+            .help("Keep a copy of each file replaced by --in-place, with .bak appended to its name."),
+        Arg::new("recipe")
+            .long("recipe")
+            .takes_value(true)
+            .value_name("FILE")
+            .help("Read options from this TOML or YAML file, by their long name, e.g. 'max-width = 1600'. Options on the command line take precedence. A 'transforms' list resizes and reduces colors in order, e.g. [{ resize = { width = 64 } }, { quantize = { colors = 16 } }]. Each entry of an 'outputs' list is a separate output of every input, with its own options, including its path, so -o can't be used with several outputs."),
+        Arg::new("verbose")
+            .long("verbose")
+            .short('v')
+            .multiple_occurrences(true)
+            .help("Log what imcon does to stderr: -v for each step, with timings, -vv for details like resizing and rendering pages."),
+        Arg::new("dry-run")
+            .long("dry-run")
+            .short('n')
+            .help("Print the outputs each input would be converted to, and their sizes, without decoding or writing anything."),
+        Arg::new("force")
             .long("force")
             .short('f')
-            .help("By default, imcon does not modify files in-place. Use this flag to allow it.")
-        )
+            .help("By default, imcon refuses to write an output over its input. Use this flag to allow it, or see --in-place."),
+    ]
 }
@@ -0,0 +1,68 @@
+use std::io;
+use ::image::ImageError;
+use libheif_rs::HeifError;
+use pdfium_render::error::{PdfiumError, PdfiumInternalError};
+
+
+/// Everything that can go wrong while reading, transforming or writing an image.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// The format couldn't be determined, e.g. from a file extension, or isn't one imcon knows.
+    #[error("Unknown format: {0}")]
+    UnknownFormat(String),
+    /// The format is known, but can't be used for what was asked of it.
+    #[error("Unsupported operation: {0}")]
+    UnsupportedOperation(String),
+    #[error("Failed to decode image: {0}")]
+    Decode(String),
+    #[error("Failed to encode image: {0}")]
+    Encode(String),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    /// The pdfium library needed for PDFs couldn't be found or loaded.
+    #[error("Pdfium library is unavailable: {0}")]
+    PdfiumUnavailable(String),
+    #[error("Page {page} is out of range, the document has {pages} pages")]
+    PageOutOfRange { page: usize, pages: usize },
+    #[error("The document is password protected")]
+    PasswordRequired,
+    #[error("Invalid color: {0}")]
+    InvalidColor(String),
+    /// An ICC profile couldn't be read, or the pixels couldn't be converted with it.
+    #[error("Color profile error: {0}")]
+    ColorProfile(String),
+}
+
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+
+impl From<ImageError> for Error {
+    fn from(e: ImageError) -> Self {
+        match e {
+            ImageError::IoError(e) => Error::Io(e),
+            ImageError::Encoding(e) => Error::Encode(e.to_string()),
+            ImageError::Unsupported(e) => Error::UnsupportedOperation(e.to_string()),
+            e => Error::Decode(e.to_string()),
+        }
+    }
+}
+
+
+impl From<HeifError> for Error {
+    fn from(e: HeifError) -> Self {
+        Error::Decode(e.to_string())
+    }
+}
+
+
+impl From<PdfiumError> for Error {
+    fn from(e: PdfiumError) -> Self {
+        match e {
+            PdfiumError::PdfiumLibraryInternalError(PdfiumInternalError::PasswordError) => Error::PasswordRequired,
+            #[cfg(not(target_arch = "wasm32"))]
+            PdfiumError::LoadLibraryError(e) => Error::PdfiumUnavailable(e.to_string()),
+            e => Error::Decode(format!("{:?}", e)),
+        }
+    }
+}
@@ -0,0 +1,271 @@
+use std::str::FromStr;
+use ::image::{DynamicImage, Rgba, RgbaImage};
+use crate::error::{Error, Result};
+use crate::image::color::{angle, function_args, parse_color, split_args};
+
+
+/// A color along a gradient, at a position from 0 to 1.
+pub type ColorStop = (Rgba<u8>, f32);
+
+
+/// A generated image, rendered at whatever size the canvas is given.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Pattern {
+    Solid(Rgba<u8>),
+    /// A gradient along a line at `angle` degrees, where 0 points up and 90 points right, as in CSS.
+    LinearGradient { angle: f32, stops: Vec<ColorStop> },
+    /// An elliptical gradient from the center to the corners, or a circular one if `circle` is set.
+    RadialGradient { circle: bool, stops: Vec<ColorStop> },
+    Checkerboard { size: u32, colors: [Rgba<u8>; 2] },
+    /// Random pixels between two colors. The same seed always gives the same pattern.
+    Noise { seed: u64, colors: [Rgba<u8>; 2] },
+}
+
+
+fn invalid(input: &str) -> Error {
+    Error::InvalidColor(input.to_string())
+}
+
+
+/// Parse gradient color stops, e.g. `red`, `blue 30%`. Stops without a position are spread evenly
+/// between their neighbours, as in CSS.
+fn color_stops(args: &[&str]) -> Result<Vec<ColorStop>> {
+    if args.len() < 2 {
+        return Err(invalid(&args.join(", ")));
+    }
+    let mut stops = Vec::new();
+    for arg in args {
+        // The position is the last word, unless that is part of the color itself.
+        let (color, position) = match arg.rsplit_once(' ').filter(|(_, p)| p.ends_with('%')) {
+            Some((color, position)) => (color, position.trim_end_matches('%').parse::<f32>().ok().map(|p| p / 100.0)),
+            None => (*arg, None),
+        };
+        stops.push((parse_color(color)?, position));
+    }
+    let last = stops.len() - 1;
+    stops[0].1 = stops[0].1.or(Some(0.0));
+    stops[last].1 = stops[last].1.or(Some(1.0));
+    let mut i = 0;
+    while i < last {
+        let next = (i + 1..=last).find(|&j| stops[j].1.is_some()).unwrap_or(last);
+        let (from, to) = (stops[i].1.unwrap_or(0.0), stops[next].1.unwrap_or(1.0).max(stops[i].1.unwrap_or(0.0)));
+        for (offset, stop) in stops[i + 1..next].iter_mut().enumerate() {
+            stop.1 = Some(from + (to - from) * (offset + 1) as f32 / (next - i) as f32);
+        }
+        i = next;
+    }
+    // Positions never go backwards along the gradient.
+    let mut previous = 0.0f32;
+    Ok(stops.into_iter().map(|(color, position)| {
+        previous = position.unwrap_or(previous).max(previous);
+        (color, previous)
+    }).collect())
+}
+
+
+/// The angle of a `to <side>` direction. Corners are always at 45 degrees, rather than depending on
+/// the aspect ratio as in CSS.
+fn direction(input: &str) -> Option<f32> {
+    let sides = input.strip_prefix("to ")?.split_whitespace().collect::<Vec<_>>();
+    let (mut x, mut y) = (0.0f32, 0.0f32);
+    for side in sides {
+        match side {
+            "top" => y = 1.0,
+            "bottom" => y = -1.0,
+            "left" => x = -1.0,
+            "right" => x = 1.0,
+            _ => return None,
+        }
+    }
+    Some(x.atan2(y).to_degrees())
+}
+
+
+fn two_colors(args: &[&str], default: [Rgba<u8>; 2]) -> Result<[Rgba<u8>; 2]> {
+    Ok(match args {
+        [] => default,
+        [a, b] => [parse_color(a)?, parse_color(b)?],
+        _ => return Err(invalid(&args.join(", "))),
+    })
+}
+
+
+impl FromStr for Pattern {
+    type Err = Error;
+
+    /// Parse a CSS color, `linear-gradient(...)`, `radial-gradient(...)`,
+    /// `checkerboard([size][, color, color])` or `noise([seed][, color, color])`.
+    fn from_str(input: &str) -> Result<Pattern> {
+        let input = input.trim();
+        let lowercase = input.to_lowercase();
+        if let Some(args) = function_args(&lowercase, "linear-gradient") {
+            let args = split_args(args);
+            let (angle, stops) = match direction(args[0]).or_else(|| angle(args[0])) {
+                Some(angle) => (angle, &args[1..]),
+                None => (180.0, &args[..]),
+            };
+            return Ok(Pattern::LinearGradient { angle, stops: color_stops(stops)? });
+        }
+        if let Some(args) = function_args(&lowercase, "radial-gradient") {
+            let args = split_args(args);
+            let (circle, stops) = match args[0] {
+                "circle" => (true, &args[1..]),
+                "ellipse" => (false, &args[1..]),
+                _ => (false, &args[..]),
+            };
+            return Ok(Pattern::RadialGradient { circle, stops: color_stops(stops)? });
+        }
+        if let Some(args) = function_args(&lowercase, "checkerboard") {
+            let args = split_args(args).into_iter().filter(|a| !a.is_empty()).collect::<Vec<_>>();
+            let (size, colors) = match args.first().and_then(|a| a.parse::<u32>().ok()) {
+                Some(size) => (size.max(1), &args[1..]),
+                None => (16, &args[..]),
+            };
+            let colors = two_colors(colors, [Rgba([255, 255, 255, 255]), Rgba([204, 204, 204, 255])])?;
+            return Ok(Pattern::Checkerboard { size, colors });
+        }
+        if let Some(args) = function_args(&lowercase, "noise") {
+            let args = split_args(args).into_iter().filter(|a| !a.is_empty()).collect::<Vec<_>>();
+            let (seed, colors) = match args.first().and_then(|a| a.parse::<u64>().ok()) {
+                Some(seed) => (seed, &args[1..]),
+                None => (0, &args[..]),
+            };
+            let colors = two_colors(colors, [Rgba([0, 0, 0, 255]), Rgba([255, 255, 255, 255])])?;
+            return Ok(Pattern::Noise { seed, colors });
+        }
+        parse_color(input).map(Pattern::Solid)
+    }
+}
+
+
+/// Interpolate between two colors with premultiplied alpha, so transparent ends don't darken the blend.
+fn mix(a: Rgba<u8>, b: Rgba<u8>, t: f32) -> Rgba<u8> {
+    let (alpha_a, alpha_b) = (a[3] as f32 / 255.0, b[3] as f32 / 255.0);
+    let alpha = alpha_a + (alpha_b - alpha_a) * t;
+    let mut out = [0, 0, 0, (alpha * 255.0).round() as u8];
+    if alpha > 0.0 {
+        for c in 0..3 {
+            let value = (a[c] as f32 * alpha_a + (b[c] as f32 * alpha_b - a[c] as f32 * alpha_a) * t) / alpha;
+            out[c] = value.round().clamp(0.0, 255.0) as u8;
+        }
+    }
+    Rgba(out)
+}
+
+
+fn gradient_color(stops: &[ColorStop], t: f32) -> Rgba<u8> {
+    let after = stops.iter().position(|&(_, position)| position > t);
+    match after {
+        Some(0) => stops[0].0,
+        None => stops[stops.len() - 1].0,
+        Some(i) => {
+            let ((a, from), (b, to)) = (stops[i - 1], stops[i]);
+            mix(a, b, (t - from) / (to - from))
+        }
+    }
+}
+
+
+/// SplitMix64, which is plenty for noise and keeps patterns stable across platforms and versions.
+fn split_mix(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+
+impl Pattern {
+    /// The functions a pattern or one of its colors can be written as.
+    const FUNCTIONS: [&'static str; 8] = ["linear-gradient", "radial-gradient", "checkerboard", "noise", "rgb", "rgba", "hsl", "hsla"];
+
+    /// Whether `input` calls one of the pattern or color functions, even with invalid arguments.
+    pub fn is_function_call(input: &str) -> bool {
+        let lowercase = input.trim().to_lowercase();
+        Self::FUNCTIONS.iter().any(|name| {
+            lowercase.strip_prefix(name).is_some_and(|args| args.trim_start().starts_with('('))
+        })
+    }
+
+    fn colors(&self) -> Vec<Rgba<u8>> {
+        match self {
+            Pattern::Solid(color) => vec![*color],
+            Pattern::LinearGradient { stops, .. } | Pattern::RadialGradient { stops, .. } => stops.iter().map(|s| s.0).collect(),
+            Pattern::Checkerboard { colors, .. } | Pattern::Noise { colors, .. } => colors.to_vec(),
+        }
+    }
+
+    /// Render the pattern. The image only has an alpha channel if one of its colors isn't opaque.
+    pub fn render(&self, width: u32, height: u32) -> DynamicImage {
+        let (w, h) = (width as f32, height as f32);
+        let image = match self {
+            Pattern::Solid(color) => RgbaImage::from_pixel(width, height, *color),
+            Pattern::LinearGradient { angle, stops } => {
+                let (sin, cos) = angle.to_radians().sin_cos();
+                let length = (w * sin).abs() + (h * cos).abs();
+                RgbaImage::from_fn(width, height, |x, y| {
+                    let (dx, dy) = (x as f32 + 0.5 - w / 2.0, y as f32 + 0.5 - h / 2.0);
+                    gradient_color(stops, (dx * sin - dy * cos) / length + 0.5)
+                })
+            }
+            Pattern::RadialGradient { circle, stops } => {
+                let (rx, ry) = if *circle {
+                    let r = (w * w + h * h).sqrt() / 2.0;
+                    (r, r)
+                } else {
+                    (w / 2.0 * 2f32.sqrt(), h / 2.0 * 2f32.sqrt())
+                };
+                RgbaImage::from_fn(width, height, |x, y| {
+                    let (dx, dy) = ((x as f32 + 0.5 - w / 2.0) / rx, (y as f32 + 0.5 - h / 2.0) / ry);
+                    gradient_color(stops, (dx * dx + dy * dy).sqrt())
+                })
+            }
+            Pattern::Checkerboard { size, colors } => {
+                RgbaImage::from_fn(width, height, |x, y| colors[((x / size + y / size) % 2) as usize])
+            }
+            Pattern::Noise { seed, colors } => {
+                let mut state = *seed;
+                let mut image = RgbaImage::new(width, height);
+                for pixel in image.pixels_mut() {
+                    *pixel = mix(colors[0], colors[1], (split_mix(&mut state) >> 40) as f32 / (1u64 << 24) as f32);
+                }
+                image
+            }
+        };
+        if self.colors().iter().all(|c| c[3] == u8::MAX) {
+            DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(image).to_rgb8())
+        } else {
+            DynamicImage::ImageRgba8(image)
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_gradient_stops() {
+        let pattern = Pattern::from_str("linear-gradient(to right, red, rgb(0, 0, 255) 80%, white)").unwrap();
+        assert_eq!(pattern, Pattern::LinearGradient {
+            angle: 90.0,
+            stops: vec![(Rgba([255, 0, 0, 255]), 0.0), (Rgba([0, 0, 255, 255]), 0.8), (Rgba([255, 255, 255, 255]), 1.0)],
+        });
+        let image = pattern.render(10, 1).to_rgb8();
+        assert_eq!(image.get_pixel(0, 0)[0], 239);
+        assert_eq!(image.get_pixel(8, 0).0, [64, 64, 255]);
+    }
+
+    #[test]
+    fn renders_seeded_noise_and_checkerboards() {
+        let noise = Pattern::from_str("noise(7)").unwrap();
+        assert_eq!(noise.render(8, 8), noise.render(8, 8));
+        assert_ne!(noise.render(8, 8), Pattern::from_str("noise(8)").unwrap().render(8, 8));
+
+        let board = Pattern::from_str("checkerboard(2, black, transparent)").unwrap().render(4, 4).to_rgba8();
+        assert_eq!(board.get_pixel(1, 1).0, [0, 0, 0, 255]);
+        assert_eq!(board.get_pixel(2, 1).0, [0, 0, 0, 0]);
+    }
+}
@@ -0,0 +1,130 @@
+use std::collections::HashMap;
+use std::io::Cursor;
+use ::image::{ColorType, DynamicImage, GenericImageView, ImageFormat};
+use ::image::codecs::jpeg::JpegEncoder;
+use crate::error::{Error, Result};
+use crate::image::{metadata, Metadata};
+
+
+/// Options for encoding an image. Each option only applies to the formats that support it.
+#[derive(Copy, Clone, Debug)]
+pub struct EncodeOptions {
+    /// JPEG and WebP quality, from 1 (smallest) to 100 (best).
+    pub quality: u8,
+    /// Write PNGs with a palette when they have at most 256 colors, as quantized images do.
+    pub indexed: bool,
+}
+
+impl Default for EncodeOptions {
+    fn default() -> Self {
+        Self { quality: 75, indexed: false }
+    }
+}
+
+impl EncodeOptions {
+    pub fn quality(mut self, quality: u8) -> Self {
+        self.quality = quality.clamp(1, 100);
+        self
+    }
+
+    pub fn indexed(mut self, indexed: bool) -> Self {
+        self.indexed = indexed;
+        self
+    }
+}
+
+
+fn png_error(e: png::EncodingError) -> Error {
+    Error::Encode(e.to_string())
+}
+
+
+/// Encode `image` as a paletted PNG, with as few bits per pixel as its colors allow. Returns `None`
+/// if the image isn't 8-bit RGB(A) or has more than 256 colors.
+fn encode_indexed_png(image: &DynamicImage) -> Result<Option<Vec<u8>>> {
+    if !matches!(image, DynamicImage::ImageRgb8(_) | DynamicImage::ImageRgba8(_)) {
+        return Ok(None);
+    }
+    let (width, height) = image.dimensions();
+    let mut palette: Vec<[u8; 4]> = Vec::new();
+    let mut indices = HashMap::new();
+    let mut pixels = Vec::with_capacity(width as usize * height as usize);
+    for (_, _, pixel) in image.pixels() {
+        let index = match indices.get(&pixel.0) {
+            Some(&index) => index,
+            None if palette.len() == 256 => return Ok(None),
+            None => {
+                let index = palette.len() as u8;
+                palette.push(pixel.0);
+                indices.insert(pixel.0, index);
+                index
+            }
+        };
+        pixels.push(index);
+    }
+    let depth = match palette.len() {
+        0..=2 => 1,
+        3..=4 => 2,
+        5..=16 => 4,
+        _ => 8,
+    };
+    let row_bytes = (width as usize * depth).div_ceil(8);
+    let mut packed = vec![0u8; row_bytes * height as usize];
+    for (y, row) in pixels.chunks(width.max(1) as usize).enumerate() {
+        for (x, &index) in row.iter().enumerate() {
+            let bit = x * depth;
+            packed[y * row_bytes + bit / 8] |= index << (8 - depth - bit % 8);
+        }
+    }
+
+    let mut data = Vec::new();
+    let mut encoder = png::Encoder::new(&mut data, width, height);
+    encoder.set_color(png::ColorType::Indexed);
+    let depth = png::BitDepth::from_u8(depth as u8)
+        .ok_or_else(|| Error::Encode(format!("Invalid PNG bit depth: {}", depth)))?;
+    encoder.set_depth(depth);
+    encoder.set_palette(palette.iter().flat_map(|c| [c[0], c[1], c[2]]).collect::<Vec<_>>());
+    // Only the entries up to the last translucent one need an alpha value.
+    let alpha = palette.iter().map(|c| c[3]).collect::<Vec<_>>();
+    if let Some(last) = alpha.iter().rposition(|&a| a != u8::MAX) {
+        encoder.set_trns(alpha[..=last].to_vec());
+    }
+    let mut writer = encoder.write_header().map_err(png_error)?;
+    writer.write_image_data(&packed).map_err(png_error)?;
+    writer.finish().map_err(png_error)?;
+    Ok(Some(data))
+}
+
+
+/// Encode `image` along with its metadata, narrowing its samples to 8 bits unless `format` can store more.
+pub fn encode_image(image: DynamicImage, metadata: &Metadata, format: ImageFormat, options: &EncodeOptions) -> Result<Vec<u8>> {
+    let mut data = Cursor::new(Vec::new());
+    match format {
+        ImageFormat::Png if options.indexed => match encode_indexed_png(&image)? {
+            Some(indexed) => data = Cursor::new(indexed),
+            None => image.write_to(&mut data, format)?,
+        },
+        ImageFormat::Png => image.write_to(&mut data, format)?,
+        ImageFormat::Tiff => image.write_to(&mut data, format)?,
+        ImageFormat::Jpeg => {
+            let image = image.to_rgb8();
+            JpegEncoder::new_with_quality(&mut data, options.quality)
+                .encode(&image, image.width(), image.height(), ColorType::Rgb8)?;
+        }
+        ImageFormat::WebP => {
+            // libwebp takes 8-bit RGB or RGBA, and only keeps an alpha channel if there is one.
+            let (width, height) = image.dimensions();
+            let encoded = if image.color().has_alpha() {
+                let rgba = image.to_rgba8();
+                webp::Encoder::from_rgba(&rgba, width, height).encode_simple(false, options.quality as f32)
+            } else {
+                let rgb = image.to_rgb8();
+                webp::Encoder::from_rgb(&rgb, width, height).encode_simple(false, options.quality as f32)
+            };
+            let encoded = encoded.map_err(|e| Error::Encode(format!("Failed to encode WebP: {:?}", e)))?;
+            data = Cursor::new(encoded.to_vec());
+        }
+        _ => DynamicImage::ImageRgba8(image.to_rgba8()).write_to(&mut data, format)?,
+    }
+    metadata::embed(data.into_inner(), format, metadata)
+}
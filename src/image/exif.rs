@@ -0,0 +1,343 @@
+/// Just enough TIFF parsing to read and patch individual EXIF entries in place, so the rest of
+/// the block (maker notes and all) is carried through byte for byte. When the structure has to
+/// change (dropping GPS, moving tags between EXIF and TIFF files), IFDs are parsed into [`Entry`]
+/// trees and written back out.
+use ::image::DynamicImage;
+
+
+const TAG_ORIENTATION: u16 = 0x0112;
+const TAG_EXIF_IFD: u16 = 0x8769;
+const TAG_GPS_IFD: u16 = 0x8825;
+const TAG_INTEROP_IFD: u16 = 0xa005;
+pub const TAG_XMP: u16 = 700;
+pub const TAG_IPTC: u16 = 33723;
+pub const TAG_ICC: u16 = 34675;
+
+/// Tags describing the pixel data of a TIFF file, which make no sense outside of it.
+const IMAGE_STRUCTURE_TAGS: &[u16] = &[
+    254, 255, 256, 257, 258, 259, 262, 266, 273, 277, 278, 279, 280, 281, 284, 317, 320, 322, 323,
+    324, 325, 330, 338, 339, 513, 514,
+];
+
+const TYPE_SHORT: u16 = 3;
+const TYPE_LONG: u16 = 4;
+const TYPE_UNDEFINED: u16 = 7;
+const ENTRY_SIZE: usize = 12;
+
+
+fn big_endian(exif: &[u8]) -> Option<bool> {
+    match exif.get(..4)? {
+        b"MM\0\x2a" => Some(true),
+        b"II\x2a\0" => Some(false),
+        _ => None,
+    }
+}
+
+fn read_u16(exif: &[u8], offset: usize, be: bool) -> Option<u16> {
+    let b = exif.get(offset..offset + 2)?;
+    Some(if be { u16::from_be_bytes([b[0], b[1]]) } else { u16::from_le_bytes([b[0], b[1]]) })
+}
+
+fn read_u32(exif: &[u8], offset: usize, be: bool) -> Option<u32> {
+    let b = exif.get(offset..offset + 4)?;
+    let b = [b[0], b[1], b[2], b[3]];
+    Some(if be { u32::from_be_bytes(b) } else { u32::from_le_bytes(b) })
+}
+
+
+/// Offset of the 12-byte entry for `tag` in the IFD at `ifd`.
+fn find_entry(exif: &[u8], ifd: usize, tag: u16, be: bool) -> Option<usize> {
+    let count = read_u16(exif, ifd, be)? as usize;
+    (0..count)
+        .map(|i| ifd + 2 + i * ENTRY_SIZE)
+        .take_while(|&entry| entry + ENTRY_SIZE <= exif.len())
+        .find(|&entry| read_u16(exif, entry, be) == Some(tag))
+}
+
+
+/// Offset of the orientation entry in IFD0, if it holds a single SHORT.
+fn orientation_entry(exif: &[u8]) -> Option<(usize, bool)> {
+    let be = big_endian(exif)?;
+    let ifd0 = read_u32(exif, 4, be)? as usize;
+    let entry = find_entry(exif, ifd0, TAG_ORIENTATION, be)?;
+    if read_u16(exif, entry + 2, be)? != TYPE_SHORT || read_u32(exif, entry + 4, be)? != 1 {
+        return None;
+    }
+    Some((entry, be))
+}
+
+
+/// The EXIF orientation, 1 through 8.
+pub fn orientation(exif: &[u8]) -> Option<u16> {
+    let (entry, be) = orientation_entry(exif)?;
+    read_u16(exif, entry + 8, be).filter(|o| (1..=8).contains(o))
+}
+
+
+/// Overwrite the orientation tag, if there is one.
+pub fn set_orientation(exif: &mut [u8], orientation: u16) {
+    if let Some((entry, be)) = orientation_entry(exif) {
+        let value = if be { orientation.to_be_bytes() } else { orientation.to_le_bytes() };
+        exif[entry + 8..entry + 10].copy_from_slice(&value);
+    }
+}
+
+
+/// An IFD entry. Values are held in big-endian byte order, and pointers to the EXIF, GPS and
+/// interoperability IFDs are resolved into `sub_ifd`.
+#[derive(Clone, Debug)]
+pub struct Entry {
+    pub tag: u16,
+    pub kind: u16,
+    pub count: u32,
+    pub data: Vec<u8>,
+    pub sub_ifd: Option<Vec<Entry>>,
+}
+
+
+impl Entry {
+    /// An entry holding an opaque block of bytes, like an ICC profile.
+    pub fn blob(tag: u16, data: Vec<u8>) -> Self {
+        Entry { tag, kind: TYPE_UNDEFINED, count: data.len() as u32, data, sub_ifd: None }
+    }
+
+    /// Whether this entry describes the pixel data of a TIFF file, rather than the picture.
+    pub fn is_image_structure(&self) -> bool {
+        IMAGE_STRUCTURE_TAGS.contains(&self.tag)
+    }
+}
+
+
+/// Size of one value of the given type, and the size of the units that need byte swapping.
+fn type_size(kind: u16) -> Option<(usize, usize)> {
+    Some(match kind {
+        1 | 2 | 6 | 7 => (1, 1),
+        3 | 8 => (2, 2),
+        4 | 9 | 11 | 13 => (4, 4),
+        5 | 10 => (8, 4),
+        12 => (8, 8),
+        _ => return None,
+    })
+}
+
+
+/// Swap every value of `data` between big and little endian.
+fn swap_byte_order(data: &mut [u8], kind: u16) {
+    if let Some((_, unit)) = type_size(kind) {
+        data.chunks_mut(unit).for_each(|c| c.reverse());
+    }
+}
+
+
+/// Blocks that are byte strings no matter what type the file claims them to be.
+fn is_blob(tag: u16) -> bool {
+    matches!(tag, TAG_XMP | TAG_IPTC | TAG_ICC)
+}
+
+
+/// Parse the IFD at `ifd`, returning its entries and the offset of the next IFD. Entries with
+/// unknown types or out-of-bounds values are skipped.
+fn parse_ifd(exif: &[u8], ifd: usize, be: bool, depth: usize) -> Option<(Vec<Entry>, u32)> {
+    let count = read_u16(exif, ifd, be)? as usize;
+    let mut entries = Vec::with_capacity(count);
+    for i in 0..count {
+        let entry = ifd + 2 + i * ENTRY_SIZE;
+        let tag = read_u16(exif, entry, be)?;
+        let kind = read_u16(exif, entry + 2, be)?;
+        let count = read_u32(exif, entry + 4, be)?;
+        let (size, _) = match type_size(kind) {
+            Some(size) => size,
+            None => continue,
+        };
+        let len = size.checked_mul(count as usize)?;
+        let data = if len <= 4 {
+            exif.get(entry + 8..entry + 8 + len)
+        } else {
+            let offset = read_u32(exif, entry + 8, be)? as usize;
+            exif.get(offset..offset.saturating_add(len))
+        };
+        let mut data = match data {
+            Some(data) => data.to_vec(),
+            None => continue,
+        };
+        if is_blob(tag) {
+            entries.push(Entry::blob(tag, data));
+            continue;
+        }
+        let sub_ifd = match tag {
+            TAG_EXIF_IFD | TAG_GPS_IFD | TAG_INTEROP_IFD if depth < 2 => {
+                let offset = read_u32(exif, entry + 8, be)? as usize;
+                match parse_ifd(exif, offset, be, depth + 1) {
+                    Some((sub_ifd, _)) => Some(sub_ifd),
+                    None => continue,
+                }
+            }
+            _ => None,
+        };
+        if !be {
+            swap_byte_order(&mut data, kind);
+        }
+        entries.push(Entry { tag, kind, count, data, sub_ifd });
+    }
+    let next = read_u32(exif, ifd + 2 + count * ENTRY_SIZE, be).unwrap_or(0);
+    Some((entries, next))
+}
+
+
+/// Parse IFD0 of an EXIF block or TIFF file. Also returns whether it is big endian.
+pub fn parse(exif: &[u8]) -> Option<(Vec<Entry>, bool)> {
+    let be = big_endian(exif)?;
+    let ifd0 = read_u32(exif, 4, be)? as usize;
+    let (entries, _) = parse_ifd(exif, ifd0, be, 0)?;
+    Some((entries, be))
+}
+
+
+fn put_u16(out: &mut [u8], pos: usize, value: u16, be: bool) {
+    let b = if be { value.to_be_bytes() } else { value.to_le_bytes() };
+    out[pos..pos + 2].copy_from_slice(&b);
+}
+
+fn put_u32(out: &mut [u8], pos: usize, value: u32, be: bool) {
+    let b = if be { value.to_be_bytes() } else { value.to_le_bytes() };
+    out[pos..pos + 4].copy_from_slice(&b);
+}
+
+
+/// Append an IFD holding `entries` (and everything they point to) to `out`, which starts at
+/// offset 0 of the file. Returns the offset of the IFD.
+fn write_ifd(out: &mut Vec<u8>, entries: &[Entry], next: u32, be: bool) -> u32 {
+    let mut entries = entries.to_vec();
+    entries.sort_by_key(|e| e.tag);
+    out.resize(out.len() + out.len() % 2, 0);
+    let ifd = out.len();
+    out.resize(ifd + 2 + entries.len() * ENTRY_SIZE + 4, 0);
+    put_u16(out, ifd, entries.len() as u16, be);
+    for (i, entry) in entries.iter().enumerate() {
+        let pos = ifd + 2 + i * ENTRY_SIZE;
+        put_u16(out, pos, entry.tag, be);
+        if let Some(sub_ifd) = &entry.sub_ifd {
+            let offset = write_ifd(out, sub_ifd, 0, be);
+            put_u16(out, pos + 2, TYPE_LONG, be);
+            put_u32(out, pos + 4, 1, be);
+            put_u32(out, pos + 8, offset, be);
+            continue;
+        }
+        let mut data = entry.data.clone();
+        if !be && !is_blob(entry.tag) {
+            swap_byte_order(&mut data, entry.kind);
+        }
+        put_u16(out, pos + 2, entry.kind, be);
+        put_u32(out, pos + 4, entry.count, be);
+        if data.len() <= 4 {
+            out[pos + 8..pos + 8 + data.len()].copy_from_slice(&data);
+        } else {
+            out.resize(out.len() + out.len() % 2, 0);
+            let offset = out.len() as u32;
+            out.extend_from_slice(&data);
+            put_u32(out, pos + 8, offset, be);
+        }
+    }
+    put_u32(out, ifd + 2 + entries.len() * ENTRY_SIZE, next, be);
+    ifd as u32
+}
+
+
+/// Write `entries` out as a standalone EXIF block.
+pub fn serialize(entries: &[Entry]) -> Vec<u8> {
+    let mut out = b"MM\0\x2a\0\0\0\x08".to_vec();
+    write_ifd(&mut out, entries, 0, true);
+    out
+}
+
+
+/// Remove the GPS IFD from an EXIF block. Returns `None` if the block can't be parsed, in which
+/// case it should be dropped entirely rather than risk leaking a location.
+pub fn strip_gps(exif: &[u8]) -> Option<Vec<u8>> {
+    let (mut entries, _) = parse(exif)?;
+    if !entries.iter().any(|e| e.tag == TAG_GPS_IFD) {
+        return Some(exif.to_vec());
+    }
+    entries.retain(|e| e.tag != TAG_GPS_IFD);
+    Some(serialize(&entries))
+}
+
+
+/// Add `extra` entries to IFD0 of an encoded TIFF file, replacing entries with the same tag. The
+/// new IFD0 is appended to the end of the file, so the existing pixel data stays where it is.
+pub fn append_to_tiff(mut tiff: Vec<u8>, extra: Vec<Entry>) -> Option<Vec<u8>> {
+    let be = big_endian(&tiff)?;
+    let ifd0 = read_u32(&tiff, 4, be)? as usize;
+    let (mut entries, next) = parse_ifd(&tiff, ifd0, be, 0)?;
+    entries.retain(|e| !extra.iter().any(|x| x.tag == e.tag));
+    entries.extend(extra);
+    let ifd0 = write_ifd(&mut tiff, &entries, next, be);
+    put_u32(&mut tiff, 4, ifd0, be);
+    Some(tiff)
+}
+
+
+/// Rotate and flip `image` so that it's upright, given its EXIF orientation.
+pub fn apply_orientation(image: DynamicImage, orientation: u16) -> DynamicImage {
+    match orientation {
+        2 => image.fliph(),
+        3 => image.rotate180(),
+        4 => image.flipv(),
+        5 => image.rotate90().fliph(),
+        6 => image.rotate90(),
+        7 => image.rotate270().fliph(),
+        8 => image.rotate270(),
+        _ => image,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::image::RgbImage;
+
+    /// A big-endian TIFF header followed by IFD0 holding just an orientation entry.
+    fn exif_with_orientation(orientation: u8) -> Vec<u8> {
+        let mut exif = b"MM\0\x2a\0\0\0\x08\0\x01".to_vec();
+        exif.extend_from_slice(&[0x01, 0x12, 0, 3, 0, 0, 0, 1, 0, orientation, 0, 0]);
+        exif.extend_from_slice(&[0, 0, 0, 0]);
+        exif
+    }
+
+    #[test]
+    fn reads_and_resets_orientation() {
+        let mut exif = exif_with_orientation(6);
+        assert_eq!(orientation(&exif), Some(6));
+        set_orientation(&mut exif, 1);
+        assert_eq!(orientation(&exif), Some(1));
+        assert_eq!(orientation(b"not exif"), None);
+    }
+
+    #[test]
+    fn strips_gps_and_round_trips() {
+        let mut entries = parse(&exif_with_orientation(3)).unwrap().0;
+        entries.push(Entry {
+            tag: TAG_GPS_IFD,
+            kind: TYPE_LONG,
+            count: 1,
+            data: vec![0; 4],
+            sub_ifd: Some(vec![Entry { tag: 2, kind: 5, count: 1, data: vec![0, 0, 0, 52, 0, 0, 0, 1], sub_ifd: None }]),
+        });
+        let exif = serialize(&entries);
+        let (parsed, _) = parse(&exif).unwrap();
+        assert_eq!(parsed[1].sub_ifd.as_ref().unwrap()[0].data, vec![0, 0, 0, 52, 0, 0, 0, 1]);
+
+        let stripped = strip_gps(&exif).unwrap();
+        let (parsed, _) = parse(&stripped).unwrap();
+        assert_eq!(parsed.len(), 1);
+        assert_eq!(orientation(&stripped), Some(3));
+    }
+
+    #[test]
+    fn orientation_swaps_dimensions() {
+        let image = DynamicImage::ImageRgb8(RgbImage::new(4, 2));
+        let upright = apply_orientation(image, 6);
+        assert_eq!((upright.width(), upright.height()), (2, 4));
+    }
+}
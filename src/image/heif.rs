@@ -1,29 +1,477 @@
+use std::collections::HashMap;
+use std::fs;
 use std::path::PathBuf;
+use std::str::FromStr;
 use image::{DynamicImage, ImageBuffer};
-use libheif_rs::{Channel, ColorSpace, HeifContext, RgbChroma};
-use anyhow::Result;
+use image::imageops::FilterType;
+use libheif_rs::{Channel, ColorSpace, HeifContext, ImageHandle, ItemId, RgbChroma};
+use crate::error::{Error, Result};
+use crate::image::{exif, Metadata};
 use crate::transform::Resize;
 
 
-pub fn create_image(ctx: HeifContext) -> Result<DynamicImage> {
-    let handle = ctx.primary_image_handle()?;
-    let image = handle.decode(ColorSpace::Rgb(RgbChroma::Rgb), false)?;
-    let width = image.width(Channel::Interleaved).map_err(|e| anyhow::anyhow!("{}", e))?;
-    let height = image.height(Channel::Interleaved).map_err(|e| anyhow::anyhow!("{}", e))?;
+/// Which images inside a HEIF container to decode. Each selected image is treated as a page.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum HeifImages {
+    /// Only the primary image. This is what most viewers show.
+    #[default]
+    Primary,
+    /// Every top-level image, e.g. each shot of a burst.
+    All,
+    /// The embedded thumbnails of every top-level image.
+    Thumbnails,
+    /// The depth maps of every top-level image.
+    Depth,
+}
+
+impl FromStr for HeifImages {
+    type Err = ();
+    fn from_str(input: &str) -> Result<HeifImages, Self::Err> {
+        Ok(match input.to_lowercase().as_str() {
+            "primary" => HeifImages::Primary,
+            "all" => HeifImages::All,
+            "thumbnails" => HeifImages::Thumbnails,
+            "depth" => HeifImages::Depth,
+            _ => return Err(()),
+        })
+    }
+}
+
+
+/// An image inside the container. Thumbnails and depth maps are addressed through their top-level image.
+#[derive(Copy, Clone)]
+enum Item {
+    TopLevel(ItemId),
+    Thumbnail(ItemId, ItemId),
+    Depth(ItemId, ItemId),
+}
+
+impl Item {
+    /// The top-level image this item belongs to, which is where its metadata is attached.
+    fn top_level_id(&self) -> ItemId {
+        match *self {
+            Item::TopLevel(id) | Item::Thumbnail(id, _) | Item::Depth(id, _) => id,
+        }
+    }
+}
+
+
+fn top_level_ids(ctx: &HeifContext) -> Vec<ItemId> {
+    let mut ids = vec![0; ctx.number_of_top_level_images()];
+    let n = ctx.top_level_image_ids(&mut ids);
+    ids.truncate(n);
+    ids
+}
+
+
+fn list_items(ctx: &HeifContext, selection: HeifImages) -> Result<Vec<Item>> {
+    let mut items = Vec::new();
+    match selection {
+        HeifImages::Primary => {
+            let primary = top_level_ids(ctx).into_iter()
+                .find(|&id| ctx.image_handle(id).map(|h| h.is_primary()).unwrap_or(false))
+                .ok_or_else(|| Error::Decode("HEIF file has no primary image".to_string()))?;
+            items.push(Item::TopLevel(primary));
+        }
+        HeifImages::All => {
+            items.extend(top_level_ids(ctx).into_iter().map(Item::TopLevel));
+        }
+        HeifImages::Thumbnails => {
+            for id in top_level_ids(ctx) {
+                let handle = ctx.image_handle(id)?;
+                let mut ids = vec![0; handle.number_of_thumbnails()];
+                let n = handle.thumbnail_ids(&mut ids);
+                items.extend(ids[..n].iter().map(|&t| Item::Thumbnail(id, t)));
+            }
+        }
+        HeifImages::Depth => {
+            for id in top_level_ids(ctx) {
+                let handle = ctx.image_handle(id)?;
+                let mut ids = vec![0; handle.number_of_depth_images().max(0) as usize];
+                let n = handle.depth_image_ids(&mut ids);
+                items.extend(ids[..n].iter().map(|&d| Item::Depth(id, d)));
+            }
+        }
+    }
+    if items.is_empty() {
+        return Err(Error::Decode(format!("HEIF file has no images matching selection: {:?}", selection)));
+    }
+    Ok(items)
+}
+
+
+/// Copy the visible pixels of a plane, dropping any row padding.
+fn plane_data(data: &[u8], stride: usize, height: u32, row_bytes: usize) -> Result<Vec<u8>> {
+    // The last row doesn't need its padding.
+    let needed = (stride * (height as usize).saturating_sub(1) + row_bytes).min(stride * height as usize);
+    if stride < row_bytes || data.len() < needed {
+        return Err(Error::Decode(format!(
+            "Decoded HEIF plane is too small: {} bytes with a stride of {}, for {} rows of {} bytes",
+            data.len(), stride, height, row_bytes,
+        )));
+    }
+    if stride == row_bytes {
+        return Ok(data[..row_bytes * height as usize].to_vec());
+    }
+    Ok(data
+        .chunks(stride)
+        .take(height as usize)
+        .flat_map(|row| &row[..row_bytes])
+        .copied()
+        .collect())
+}
+
+
+/// Widen `bits`-deep samples stored in 16-bit little endian words to the full 16-bit range.
+fn widen_samples(data: &[u8], bits: u8) -> Vec<u16> {
+    let bits = bits.clamp(1, 16) as u32;
+    data.chunks_exact(2)
+        .map(|b| {
+            let v = u16::from_le_bytes([b[0], b[1]]) as u32;
+            ((v << (16 - bits)) | (v >> (2 * bits).saturating_sub(16))) as u16
+        })
+        .collect()
+}
+
+
+/// Decode to RGBA when the image has alpha, and to 16 bits per channel when it is deeper than 8 bits.
+/// Unless `auto_orient`, the container's rotation and mirroring aren't applied.
+fn decode_color(handle: &ImageHandle, auto_orient: bool) -> Result<DynamicImage> {
+    let has_alpha = handle.has_alpha_channel();
+    let bit_depth = handle.luma_bits_per_pixel();
+    let high_bit_depth = bit_depth > 8;
+    let chroma = match (high_bit_depth, has_alpha) {
+        (false, false) => RgbChroma::Rgb,
+        (false, true) => RgbChroma::Rgba,
+        (true, false) => RgbChroma::HdrRgbLe,
+        (true, true) => RgbChroma::HdrRgbaLe,
+    };
+    let image = handle.decode(ColorSpace::Rgb(chroma), !auto_orient)?;
+    let width = image.width(Channel::Interleaved)?;
+    let height = image.height(Channel::Interleaved)?;
+    let planes = image.planes();
+    let interleaved_plane = planes.interleaved
+        .ok_or_else(|| Error::Decode("Decoded HEIF image has no interleaved plane".to_string()))?;
+    let channels = if has_alpha { 4 } else { 3 };
+    let bytes_per_sample = if high_bit_depth { 2 } else { 1 };
+    let data = plane_data(interleaved_plane.data, interleaved_plane.stride, height, width as usize * channels * bytes_per_sample)?;
+    let image = match (high_bit_depth, has_alpha) {
+        (false, false) => ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageRgb8),
+        (false, true) => ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageRgba8),
+        (true, false) => ImageBuffer::from_raw(width, height, widen_samples(&data, bit_depth)).map(DynamicImage::ImageRgb16),
+        (true, true) => ImageBuffer::from_raw(width, height, widen_samples(&data, bit_depth)).map(DynamicImage::ImageRgba16),
+    };
+    image.ok_or_else(|| Error::Decode("Failed to create image buffer".to_string()))
+}
+
+
+fn decode_depth(handle: &ImageHandle, auto_orient: bool) -> Result<DynamicImage> {
+    let image = handle.decode(ColorSpace::Monochrome, !auto_orient)?;
+    let width = image.width(Channel::Y)?;
+    let height = image.height(Channel::Y)?;
     let planes = image.planes();
-    let interleaved_plane = planes.interleaved.unwrap();
-    ImageBuffer::from_raw(width, height, interleaved_plane.data.to_owned())
-        .map(DynamicImage::ImageRgb8)
-        .ok_or(anyhow::anyhow!("Failed to create image buffer"))
+    let y_plane = planes.y
+        .ok_or_else(|| Error::Decode("Decoded HEIF depth image has no luma plane".to_string()))?;
+    let data = plane_data(y_plane.data, y_plane.stride, height, width as usize)?;
+    ImageBuffer::from_raw(width, height, data)
+        .map(DynamicImage::ImageLuma8)
+        .ok_or_else(|| Error::Decode("Failed to create image buffer".to_string()))
+}
+
+
+fn decode_item(ctx: &HeifContext, item: Item, auto_orient: bool) -> Result<DynamicImage> {
+    match item {
+        Item::TopLevel(id) => decode_color(&ctx.image_handle(id)?, auto_orient),
+        Item::Thumbnail(id, thumbnail_id) => {
+            let handle = ctx.image_handle(id)?;
+            let thumbnail = handle.thumbnail(thumbnail_id)?;
+            decode_color(&thumbnail, auto_orient)
+        }
+        Item::Depth(id, depth_id) => {
+            let handle = ctx.image_handle(id)?;
+            let depth = handle.depth_image_handle(depth_id)?;
+            decode_depth(&depth, auto_orient)
+        }
+    }
+}
+
+
+/// The smallest embedded thumbnail that is at least `width`x`height`, if any.
+fn thumbnail_covering(handle: &ImageHandle, width: u32, height: u32) -> Result<Option<ItemId>> {
+    let mut ids = vec![0; handle.number_of_thumbnails()];
+    let n = handle.thumbnail_ids(&mut ids);
+    let mut covering = Vec::new();
+    for &id in &ids[..n] {
+        let thumbnail = handle.thumbnail(id)?;
+        if thumbnail.width() >= width && thumbnail.height() >= height {
+            covering.push((thumbnail.width() as u64 * thumbnail.height() as u64, id));
+        }
+    }
+    Ok(covering.into_iter().min().map(|(_, id)| id))
+}
+
+
+/// Decode `item` and apply `resize` to it. Target dimensions are computed from the full image, but when
+/// an embedded thumbnail is big enough to produce them, the thumbnail is decoded instead.
+fn decode_item_resized(ctx: &HeifContext, item: Item, auto_orient: bool, resize: Option<Resize>) -> Result<DynamicImage> {
+    let resize = match resize {
+        Some(resize) => resize,
+        None => return decode_item(ctx, item, auto_orient),
+    };
+    let image = match item {
+        Item::TopLevel(id) => {
+            let handle = ctx.image_handle(id)?;
+            let (width, height) = resize.calculate_dimensions(handle.width(), handle.height());
+            match thumbnail_covering(&handle, width, height)? {
+                Some(thumbnail_id) => decode_item(ctx, Item::Thumbnail(id, thumbnail_id), auto_orient)?,
+                None => decode_item(ctx, item, auto_orient)?,
+            }
+            .resize(width, height, FilterType::Lanczos3)
+        }
+        _ => {
+            let image = decode_item(ctx, item, auto_orient)?;
+            let (width, height) = resize.calculate_dimensions(image.width(), image.height());
+            image.resize(width, height, FilterType::Lanczos3)
+        }
+    };
+    Ok(image)
+}
+
+
+/// The boxes directly inside `data`, as (type, contents) pairs.
+fn boxes(mut data: &[u8]) -> Vec<([u8; 4], &[u8])> {
+    let mut boxes = Vec::new();
+    while data.len() >= 8 {
+        let kind = [data[4], data[5], data[6], data[7]];
+        let (header, size) = match u32::from_be_bytes([data[0], data[1], data[2], data[3]]) {
+            0 => (8, data.len() as u64),
+            1 if data.len() >= 16 => (16, u64::from_be_bytes([data[8], data[9], data[10], data[11], data[12], data[13], data[14], data[15]])),
+            size => (8, size as u64),
+        };
+        if size < header || size > data.len() as u64 {
+            break;
+        }
+        boxes.push((kind, &data[header as usize..size as usize]));
+        data = &data[size as usize..];
+    }
+    boxes
+}
+
+
+fn child<'a>(data: &'a [u8], kind: &[u8; 4]) -> Option<&'a [u8]> {
+    boxes(data).into_iter().find(|(k, _)| k == kind).map(|(_, contents)| contents)
+}
+
+
+fn read_be(data: &[u8], pos: usize, len: usize) -> Option<u32> {
+    Some(data.get(pos..pos + len)?.iter().fold(0, |v, &b| (v << 8) | b as u32))
+}
+
+
+/// The ICC profiles attached to items through `colr` properties. libheif-rs doesn't expose them,
+/// so they are read from the container's item property boxes directly. A file without item
+/// properties has none, but truncated property associations are an error.
+fn icc_profiles(data: &[u8]) -> Result<HashMap<ItemId, Vec<u8>>> {
+    let mut profiles = HashMap::new();
+    let iprp = match child(data, b"meta").and_then(|meta| child(meta.get(4..)?, b"iprp")) {
+        Some(iprp) => iprp,
+        None => return Ok(profiles),
+    };
+    let properties = child(iprp, b"ipco").map(boxes).unwrap_or_default();
+    let truncated = || Error::Decode("HEIF item property associations are truncated".to_string());
+    for (_, ipma) in boxes(iprp).into_iter().filter(|(kind, _)| kind == b"ipma") {
+        let (version, flags) = (*ipma.first().ok_or_else(truncated)?, *ipma.get(3).ok_or_else(truncated)?);
+        let id_len = if version < 1 { 2 } else { 4 };
+        let (index_len, index_mask) = if flags & 1 != 0 { (2, 0x7fff) } else { (1, 0x7f) };
+        let mut pos = 8;
+        for _ in 0..read_be(ipma, 4, 4).ok_or_else(truncated)? {
+            let id = read_be(ipma, pos, id_len).ok_or_else(truncated)?;
+            let n_properties = read_be(ipma, pos + id_len, 1).ok_or_else(truncated)?;
+            pos += id_len + 1;
+            for _ in 0..n_properties {
+                // Property indices are 1-based, 0 means none.
+                let index = (read_be(ipma, pos, index_len).ok_or_else(truncated)? & index_mask) as usize;
+                pos += index_len;
+                let colr = match index.checked_sub(1).and_then(|i| properties.get(i)).filter(|(kind, _)| kind == b"colr") {
+                    Some((_, colr)) => colr,
+                    None => continue,
+                };
+                if colr.starts_with(b"prof") || colr.starts_with(b"rICC") {
+                    profiles.insert(id, colr[4..].to_vec());
+                }
+            }
+        }
+    }
+    Ok(profiles)
+}
+
+
+/// Read the EXIF and XMP blocks attached to an image. With `auto_orient`, the EXIF orientation is
+/// reset, as the image is decoded upright.
+fn read_metadata(handle: &ImageHandle, auto_orient: bool) -> Result<Metadata> {
+    let mut metadata = Metadata::default();
+    let mut ids = vec![0; handle.number_of_metadata_blocks("").max(0) as usize];
+    let n = handle.metadata_block_ids("", &mut ids);
+    for &id in &ids[..n] {
+        match (handle.metadata_type(id), handle.metadata_content_type(id)) {
+            (Some("Exif"), _) => {
+                // The block starts with a big-endian offset from its end to the TIFF header.
+                let data = handle.metadata(id)?;
+                let offset = match data.get(..4) {
+                    Some(b) => u32::from_be_bytes([b[0], b[1], b[2], b[3]]) as usize + 4,
+                    None => continue,
+                };
+                metadata.exif = data.get(offset..).map(|tiff| {
+                    // libheif applies the container's rotation and mirroring while decoding.
+                    let mut tiff = tiff.to_vec();
+                    if auto_orient {
+                        exif::set_orientation(&mut tiff, 1);
+                    }
+                    tiff
+                });
+            }
+            (Some("mime"), Some("application/rdf+xml")) => {
+                metadata.xmp = Some(handle.metadata(id)?);
+            }
+            _ => {}
+        }
+    }
+    Ok(metadata)
+}
+
+
+fn decode_item_with_metadata(ctx: &HeifContext, profiles: &HashMap<ItemId, Vec<u8>>, item: Item, auto_orient: bool, resize: Option<Resize>) -> Result<(DynamicImage, Metadata)> {
+    let mut metadata = read_metadata(&ctx.image_handle(item.top_level_id())?, auto_orient)?;
+    metadata.icc = match item {
+        Item::TopLevel(id) => profiles.get(&id),
+        Item::Thumbnail(id, thumbnail_id) => profiles.get(&thumbnail_id).or_else(|| profiles.get(&id)),
+        Item::Depth(..) => None,
+    }.cloned();
+    let image = decode_item_resized(ctx, item, auto_orient, resize)?;
+    Ok((image, metadata))
+}
+
+
+/// The selected images of a HEIF file, parsed once to decode them one at a time.
+pub struct HeifFile {
+    ctx: HeifContext,
+    profiles: HashMap<ItemId, Vec<u8>>,
+    items: Vec<Item>,
+    /// libheif reads from the data without copying it, so it's declared after `ctx` to outlive it.
+    _data: Vec<u8>,
 }
 
-pub fn open_image(path: &PathBuf, _resize: Option<Resize>) -> Result<DynamicImage> {
-    let im = HeifContext::read_from_file(path.to_string_lossy().as_ref())?;
-    create_image(im)
+impl HeifFile {
+    pub fn new(data: Vec<u8>, selection: HeifImages) -> Result<Self> {
+        let ctx = HeifContext::read_from_bytes(&data)?;
+        let profiles = icc_profiles(&data)?;
+        let items = list_items(&ctx, selection)?;
+        Ok(Self { ctx, profiles, items, _data: data })
+    }
+
+    pub fn image_count(&self) -> usize {
+        self.items.len()
+    }
+
+    /// The dimensions of the `index`th image of the selection, without decoding it.
+    pub fn image_size(&self, index: usize) -> Result<(u32, u32)> {
+        let item = *self.items.get(index)
+            .ok_or(Error::PageOutOfRange { page: index, pages: self.items.len() })?;
+        item_size(&self.ctx, item)
+    }
+
+    /// Decode the `index`th image of the selection, resized if requested.
+    pub fn decode(&self, index: usize, auto_orient: bool, resize: Option<Resize>) -> Result<(DynamicImage, Metadata)> {
+        let item = *self.items.get(index)
+            .ok_or(Error::PageOutOfRange { page: index, pages: self.items.len() })?;
+        decode_item_with_metadata(&self.ctx, &self.profiles, item, auto_orient, resize)
+    }
 }
 
 
-pub fn read_image(data: &[u8], _resize: Option<Resize>) -> Result<DynamicImage> {
+pub fn open_image(path: &PathBuf, selection: HeifImages, auto_orient: bool, resize: Option<Resize>) -> Result<(DynamicImage, Metadata)> {
+    read_image(fs::read(path)?, selection, auto_orient, resize)
+}
+
+
+/// Decode the first image of the selection, resized if requested.
+pub fn read_image(data: Vec<u8>, selection: HeifImages, auto_orient: bool, resize: Option<Resize>) -> Result<(DynamicImage, Metadata)> {
+    HeifFile::new(data, selection)?.decode(0, auto_orient, resize)
+}
+
+
+fn item_size(ctx: &HeifContext, item: Item) -> Result<(u32, u32)> {
+    let handle = ctx.image_handle(item.top_level_id())?;
+    Ok(match item {
+        Item::TopLevel(_) => (handle.width(), handle.height()),
+        Item::Thumbnail(_, id) => {
+            let thumbnail = handle.thumbnail(id)?;
+            (thumbnail.width(), thumbnail.height())
+        }
+        Item::Depth(_, id) => {
+            let depth = handle.depth_image_handle(id)?;
+            (depth.width(), depth.height())
+        }
+    })
+}
+
+
+/// The dimensions of the images in the selection, without decoding them.
+pub fn item_sizes(data: &[u8], selection: HeifImages) -> Result<Vec<(u32, u32)>> {
     let ctx = HeifContext::read_from_bytes(data)?;
-    create_image(ctx)
+    list_items(&ctx, selection)?.into_iter().map(|item| item_size(&ctx, item)).collect()
+}
+
+
+pub fn transform_all_images_from_path<S, T>(path: &PathBuf, selection: HeifImages, auto_orient: bool, resize: Option<Resize>, transform: S) -> Result<Vec<T>>
+    where
+        S: Fn(usize, usize, DynamicImage, Metadata) -> Result<T>
+{
+    let file = HeifFile::new(fs::read(path)?, selection)?;
+    let num_images = file.image_count();
+    let mut results = Vec::new();
+    for i in 0..num_images {
+        log::debug!("Decoding image {} of {}", i + 1, num_images);
+        let (image, metadata) = file.decode(i, auto_orient, resize)?;
+        results.push(transform(i, num_images, image, metadata)?);
+    }
+    Ok(results)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn full_box(kind: &[u8; 4], contents: &[u8]) -> Vec<u8> {
+        [&(contents.len() as u32 + 8).to_be_bytes()[..], kind, contents].concat()
+    }
+
+    #[test]
+    fn reads_icc_profiles_from_item_properties() {
+        let ipco = full_box(b"ipco", &[
+            full_box(b"ispe", &[0; 12]),
+            full_box(b"colr", b"profICC!"),
+        ].concat());
+        // Version 0, one entry: item 1 has properties 1 and 2, the latter marked essential.
+        let ipma = full_box(b"ipma", &[0, 0, 0, 0, 0, 0, 0, 1, 0, 1, 2, 0x01, 0x82]);
+        let meta = full_box(b"meta", &[&[0, 0, 0, 0][..], &full_box(b"iprp", &[ipco, ipma].concat())].concat());
+        let data = [full_box(b"ftyp", b"heic"), meta].concat();
+        let profiles = icc_profiles(&data).unwrap();
+        assert_eq!(profiles.get(&1).map(|icc| icc.as_slice()), Some(&b"ICC!"[..]));
+    }
+
+    #[test]
+    fn rejects_short_planes() {
+        let data = [1, 2, 0, 3, 4, 0, 5, 6];
+        assert_eq!(plane_data(&data, 3, 3, 2).unwrap(), vec![1, 2, 3, 4, 5, 6]);
+        assert!(matches!(plane_data(&data, 3, 4, 2), Err(Error::Decode(_))));
+        assert!(matches!(plane_data(&data, 1, 3, 2), Err(Error::Decode(_))));
+    }
+
+    #[test]
+    fn widen_ten_bit_samples() {
+        let data = [0x00, 0x00, 0xff, 0x03, 0x00, 0x02];
+        assert_eq!(widen_samples(&data, 10), vec![0x0000, 0xffff, 0x8020]);
+    }
 }
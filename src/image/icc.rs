@@ -0,0 +1,133 @@
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
+use ::image::{DynamicImage, ImageBuffer};
+use crate::error::{Error, Result};
+use lcms2::{ColorSpaceSignature, Flags, InfoType, Intent, Locale, PixelFormat, Profile, Transform};
+
+
+/// The color profile to convert pixels into.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ColorProfile {
+    Srgb,
+    /// An ICC profile read from a file.
+    File(PathBuf),
+}
+
+impl FromStr for ColorProfile {
+    type Err = ();
+    fn from_str(input: &str) -> Result<ColorProfile, Self::Err> {
+        Ok(match input.to_lowercase().as_str() {
+            "srgb" => ColorProfile::Srgb,
+            _ => ColorProfile::File(PathBuf::from(input)),
+        })
+    }
+}
+
+impl ColorProfile {
+    /// The profile as ICC data, ready to embed in an output file.
+    pub fn icc(&self) -> Result<Vec<u8>> {
+        match self {
+            ColorProfile::Srgb => Profile::new_srgb().icc()
+                .map_err(|e| Error::ColorProfile(format!("Failed to serialize sRGB profile: {}", e))),
+            ColorProfile::File(path) => {
+                let icc = fs::read(path)?;
+                Profile::new_icc(&icc)
+                    .map_err(|e| Error::ColorProfile(format!("Invalid ICC profile {}: {}", path.display(), e)))?;
+                Ok(icc)
+            }
+        }
+    }
+}
+
+
+/// The human readable name stored in an ICC profile, e.g. "Display P3".
+pub fn description(icc: &[u8]) -> Option<String> {
+    Profile::new_icc(icc).ok()?.info(InfoType::Description, Locale::none())
+}
+
+
+fn to_bytes(samples: Vec<u16>) -> Vec<u8> {
+    samples.into_iter().flat_map(u16::to_ne_bytes).collect()
+}
+
+fn to_samples(bytes: &[u8]) -> Vec<u16> {
+    bytes.chunks_exact(2).map(|b| u16::from_ne_bytes([b[0], b[1]])).collect()
+}
+
+
+/// Convert the pixels of `image` from the `source` profile to the `target` profile. Images without a
+/// profile, or with one that doesn't describe their pixels (e.g. CMYK), are taken to be sRGB.
+/// The result is always RGB, keeping alpha and 16-bit depth.
+pub fn convert(image: DynamicImage, source: Option<&[u8]>, target: &[u8]) -> Result<DynamicImage> {
+    if source == Some(target) {
+        return Ok(image);
+    }
+    let target = Profile::new_icc(target)
+        .map_err(|e| Error::ColorProfile(format!("Invalid target ICC profile: {}", e)))?;
+    if target.color_space() != ColorSpaceSignature::RgbData {
+        return Err(Error::ColorProfile("Target ICC profile must be an RGB profile".to_string()));
+    }
+    let color = image.color();
+    let gray_image = color.channel_count() <= 2;
+    let source = match source.and_then(|icc| Profile::new_icc(icc).ok()) {
+        Some(profile) if profile.color_space() == ColorSpaceSignature::RgbData => profile,
+        Some(profile) if profile.color_space() == ColorSpaceSignature::GrayData && gray_image => profile,
+        _ => Profile::new_srgb(),
+    };
+    let gray = source.color_space() == ColorSpaceSignature::GrayData;
+    let has_alpha = color.has_alpha();
+    let deep = color.bytes_per_pixel() / color.channel_count() > 1;
+    let (width, height) = (image.width(), image.height());
+    let (in_format, data) = match (gray, deep, has_alpha) {
+        (true, false, false) => (PixelFormat::GRAY_8, image.into_luma8().into_raw()),
+        (true, false, true) => (PixelFormat::GRAYA_8, image.into_luma_alpha8().into_raw()),
+        (true, true, false) => (PixelFormat::GRAY_16, to_bytes(image.into_luma16().into_raw())),
+        (true, true, true) => (PixelFormat::GRAYA_16, to_bytes(image.into_luma_alpha16().into_raw())),
+        (false, false, false) => (PixelFormat::RGB_8, image.into_rgb8().into_raw()),
+        (false, false, true) => (PixelFormat::RGBA_8, image.into_rgba8().into_raw()),
+        (false, true, false) => (PixelFormat::RGB_16, to_bytes(image.into_rgb16().into_raw())),
+        (false, true, true) => (PixelFormat::RGBA_16, to_bytes(image.into_rgba16().into_raw())),
+    };
+    let out_format = match (deep, has_alpha) {
+        (false, false) => PixelFormat::RGB_8,
+        (false, true) => PixelFormat::RGBA_8,
+        (true, false) => PixelFormat::RGB_16,
+        (true, true) => PixelFormat::RGBA_16,
+    };
+    let transform: Transform<u8, u8> = Transform::new_flags(&source, in_format, &target, out_format, Intent::Perceptual, Flags::COPY_ALPHA)
+        .map_err(|e| Error::ColorProfile(format!("Failed to create color transform: {}", e)))?;
+    let mut out = vec![0; width as usize * height as usize * out_format.bytes_per_pixel()];
+    transform.transform_pixels(&data, &mut out);
+    let image = match (deep, has_alpha) {
+        (false, false) => ImageBuffer::from_raw(width, height, out).map(DynamicImage::ImageRgb8),
+        (false, true) => ImageBuffer::from_raw(width, height, out).map(DynamicImage::ImageRgba8),
+        (true, false) => ImageBuffer::from_raw(width, height, to_samples(&out)).map(DynamicImage::ImageRgb16),
+        (true, true) => ImageBuffer::from_raw(width, height, to_samples(&out)).map(DynamicImage::ImageRgba16),
+    };
+    image.ok_or_else(|| Error::ColorProfile("Failed to create image buffer".to_string()))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::image::{Rgb, RgbImage};
+
+    fn display_p3() -> Vec<u8> {
+        let xy = |x, y| lcms2::CIExyY { x, y, Y: 1.0 };
+        let primaries = lcms2::CIExyYTRIPLE { Red: xy(0.680, 0.320), Green: xy(0.265, 0.690), Blue: xy(0.150, 0.060) };
+        let curve = lcms2::ToneCurve::new_parametric(4, &[2.4, 1.0 / 1.055, 0.055 / 1.055, 1.0 / 12.92, 0.04045]).unwrap();
+        Profile::new_rgb(&xy(0.3127, 0.3290), &primaries, &[&curve, &curve, &curve]).unwrap().icc().unwrap()
+    }
+
+    #[test]
+    fn converts_display_p3_to_srgb() {
+        let srgb = ColorProfile::Srgb.icc().unwrap();
+        let image = RgbImage::from_fn(2, 1, |x, _| if x == 0 { Rgb([128, 128, 128]) } else { Rgb([100, 150, 200]) });
+        let converted = convert(DynamicImage::ImageRgb8(image), Some(&display_p3()), &srgb).unwrap().to_rgb8();
+        let gray = converted.get_pixel(0, 0);
+        assert!(gray.0.iter().all(|&c| (127..=129).contains(&c)), "{:?}", gray);
+        assert_ne!(converted.get_pixel(1, 0), &Rgb([100, 150, 200]));
+    }
+}
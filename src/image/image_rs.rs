@@ -1,23 +1,51 @@
-use std::fs::File;
-use std::io::{BufReader, Cursor};
+use std::fs;
+use std::io::Cursor;
 use std::path::PathBuf;
 use ::image::{DynamicImage};
-use anyhow::Result;
-use crate::image::Format;
+use crate::error::Result;
+use crate::image::{exif, metadata, Format, Metadata};
 
 
+/// Rotate the image upright according to its EXIF orientation, and reset the tag to match.
+fn orient_upright(image: DynamicImage, metadata: &mut Metadata) -> DynamicImage {
+    let exif = match metadata.exif.as_mut() {
+        Some(exif) => exif,
+        None => return image,
+    };
+    match exif::orientation(exif) {
+        Some(orientation) => {
+            exif::set_orientation(exif, 1);
+            exif::apply_orientation(image, orientation)
+        }
+        None => image,
+    }
+}
+
+
+pub fn open_image(path: &PathBuf, format: Format, auto_orient: bool) -> Result<(DynamicImage, Metadata)> {
+    let data = fs::read(path)?;
+    read_image(data, format, auto_orient)
+}
 
-pub fn open_image(path: &PathBuf, format: Format) -> Result<DynamicImage> {
-    let format = format.try_into()?;
-    let f = File::open(path)?;
-    let f = BufReader::new(f);
-    ::image::load(f, format)
-        .map_err(|e| anyhow::anyhow!("{}", e))
+pub fn read_image(data: Vec<u8>, format: Format, auto_orient: bool) -> Result<(DynamicImage, Metadata)> {
+    let image_format = format.try_into()?;
+    let image = ::image::load(Cursor::new(&data), image_format)?;
+    let mut metadata = metadata::read(data);
+    let image = if auto_orient {
+        orient_upright(image, &mut metadata)
+    } else {
+        image
+    };
+    Ok((image, metadata))
 }
 
-pub fn read_image(data: Vec<u8>, format: Format) -> Result<DynamicImage> {
-    let format = format.try_into()?;
-    let f = Cursor::new(data);
-    ::image::load(f, format)
-        .map_err(|e| anyhow::anyhow!("{}", e))
-}
\ No newline at end of file
+
+/// The dimensions of the image as it would be decoded, read from its header and EXIF orientation.
+pub fn read_dimensions(data: Vec<u8>, format: Format, auto_orient: bool) -> Result<(u32, u32)> {
+    let image_format = format.try_into()?;
+    let (width, height) = ::image::io::Reader::with_format(Cursor::new(&data), image_format).into_dimensions()?;
+    let metadata = metadata::read(data);
+    // Orientations 5 to 8 turn the image on its side.
+    let turned = auto_orient && metadata.exif.as_deref().and_then(exif::orientation).is_some_and(|o| o >= 5);
+    Ok(if turned { (height, width) } else { (width, height) })
+}
@@ -0,0 +1,424 @@
+use std::fmt;
+use std::str::FromStr;
+use ::image::ImageFormat;
+use ::exif::{In, Tag, Value};
+use crate::error::{Error, Result};
+use img_parts::{Bytes, DynImage, ImageEXIF, ImageICC};
+use img_parts::jpeg::{markers, Jpeg, JpegSegment};
+use img_parts::png::{Png, PngChunk};
+use img_parts::riff::{RiffChunk, RiffContent};
+use img_parts::webp::{WebP, CHUNK_ALPH, CHUNK_ANIM, CHUNK_EXIF, CHUNK_ICCP, CHUNK_VP8L, CHUNK_VP8X, CHUNK_XMP};
+use once_cell::sync::Lazy;
+use regex::bytes::Regex;
+use crate::image::exif::{self, Entry};
+use crate::image::icc;
+use crate::util::DateTime;
+
+
+const XMP_JPEG_PREFIX: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+const IPTC_JPEG_PREFIX: &[u8] = b"Photoshop 3.0\0";
+/// The Photoshop image resource holding IPTC data in a JPEG APP13 segment.
+const IPTC_RESOURCE_ID: u16 = 0x0404;
+/// iTXt keyword, followed by an uncompressed, untranslated text header.
+const XMP_PNG_PREFIX: &[u8] = b"XML:com.adobe.xmp\0\0\0\0\0";
+const PNG_CHUNK_ITXT: [u8; 4] = *b"iTXt";
+
+
+/// Metadata that travels with the pixels of an image.
+#[derive(Clone, Debug, Default)]
+pub struct Metadata {
+    pub width: usize,
+    pub height: usize,
+    /// Raw EXIF data, starting at the TIFF header.
+    pub exif: Option<Vec<u8>>,
+    /// Raw XMP packet.
+    pub xmp: Option<Vec<u8>>,
+    /// Raw IPTC-IIM data, without the Photoshop image resource wrapping it in JPEG files.
+    pub iptc: Option<Vec<u8>>,
+    /// Embedded ICC color profile.
+    pub icc: Option<Vec<u8>>,
+}
+
+
+/// The kinds of metadata blocks that can be kept or stripped.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MetadataKind {
+    Exif,
+    Xmp,
+    Iptc,
+    Icc,
+}
+
+impl MetadataKind {
+    pub const ALL: [MetadataKind; 4] = [MetadataKind::Exif, MetadataKind::Xmp, MetadataKind::Iptc, MetadataKind::Icc];
+}
+
+impl FromStr for MetadataKind {
+    type Err = ();
+    fn from_str(input: &str) -> Result<MetadataKind, Self::Err> {
+        Ok(match input.to_lowercase().as_str() {
+            "exif" => MetadataKind::Exif,
+            "xmp" => MetadataKind::Xmp,
+            "iptc" => MetadataKind::Iptc,
+            "icc" => MetadataKind::Icc,
+            _ => return Err(()),
+        })
+    }
+}
+
+
+impl Metadata {
+    fn is_empty(&self) -> bool {
+        self.exif.is_none() && self.xmp.is_none() && self.iptc.is_none() && self.icc.is_none()
+    }
+
+    /// When the picture was taken, from the EXIF `DateTimeOriginal` tag, or else `DateTime`.
+    pub fn capture_date(&self) -> Option<DateTime> {
+        let fields = ::exif::Reader::new().read_raw(self.exif.clone()?).ok()?;
+        let date = [Tag::DateTimeOriginal, Tag::DateTime].iter().find_map(|&tag| {
+            match &fields.get_field(tag, In::PRIMARY)?.value {
+                Value::Ascii(values) => ::exif::DateTime::from_ascii(values.first()?).ok(),
+                _ => None,
+            }
+        })?;
+        Some(DateTime {
+            year: date.year as i64,
+            month: date.month as u32,
+            day: date.day as u32,
+            hour: date.hour as u32,
+            minute: date.minute as u32,
+            second: date.second as u32,
+        })
+    }
+
+    /// Drop every metadata block, keeping only the dimensions.
+    pub fn stripped(self) -> Self {
+        self.filter(&[], false)
+    }
+
+    /// Keep only the metadata blocks of the given kinds, optionally removing GPS data from EXIF
+    /// and XMP.
+    pub fn filter(self, keep: &[MetadataKind], strip_gps: bool) -> Self {
+        let Metadata { width, height, exif, xmp, iptc, icc } = self;
+        let mut exif = exif.filter(|_| keep.contains(&MetadataKind::Exif));
+        let mut xmp = xmp.filter(|_| keep.contains(&MetadataKind::Xmp));
+        if strip_gps {
+            exif = exif.and_then(|exif| exif::strip_gps(&exif));
+            xmp = xmp.map(|xmp| strip_xmp_gps(&xmp));
+        }
+        Metadata {
+            width,
+            height,
+            exif,
+            xmp,
+            iptc: iptc.filter(|_| keep.contains(&MetadataKind::Iptc)),
+            icc: icc.filter(|_| keep.contains(&MetadataKind::Icc)),
+        }
+    }
+}
+
+
+impl fmt::Display for Metadata {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "width: {}", self.width)?;
+        writeln!(f, "height: {}", self.height)?;
+        if let Some(exif) = &self.exif {
+            match ::exif::Reader::new().read_raw(exif.clone()) {
+                Ok(fields) => {
+                    writeln!(f, "exif:")?;
+                    for field in fields.fields() {
+                        writeln!(f, "  {}: {}", field.tag, field.display_value().with_unit(&fields))?;
+                    }
+                }
+                Err(_) => writeln!(f, "exif: {} bytes (unreadable)", exif.len())?,
+            }
+        }
+        if let Some(xmp) = &self.xmp {
+            writeln!(f, "xmp: {}", String::from_utf8_lossy(xmp).trim_end_matches('\0').trim())?;
+        }
+        if let Some(iptc) = &self.iptc {
+            writeln!(f, "iptc: {} bytes", iptc.len())?;
+        }
+        if let Some(icc) = &self.icc {
+            match icc::description(icc) {
+                Some(description) => writeln!(f, "icc: {} ({} bytes)", description, icc.len())?,
+                None => writeln!(f, "icc: {} bytes", icc.len())?,
+            }
+        }
+        Ok(())
+    }
+}
+
+
+/// Remove the `exif:GPS*` properties of an XMP packet, whether written as attributes or elements.
+fn strip_xmp_gps(xmp: &[u8]) -> Vec<u8> {
+    static GPS_PROPERTIES: Lazy<Regex> = Lazy::new(|| Regex::new(concat!(
+        r#"(?s)\s+exif:GPS\w+\s*=\s*("[^"]*"|'[^']*')"#,
+        r#"|<exif:GPS\w+[^>]*/>"#,
+        r#"|<exif:GPS\w+[^>]*>.*?</exif:GPS\w+>"#,
+    )).unwrap());
+    GPS_PROPERTIES.replace_all(xmp, &b""[..]).into_owned()
+}
+
+
+/// The IPTC data in the Photoshop image resources of a JPEG APP13 segment.
+fn unwrap_iptc(app13: &[u8]) -> Option<Vec<u8>> {
+    let mut resources = app13.strip_prefix(IPTC_JPEG_PREFIX)?;
+    while resources.starts_with(b"8BIM") {
+        let id = u16::from_be_bytes([*resources.get(4)?, *resources.get(5)?]);
+        // The name is a Pascal string, padded to an even length like the data.
+        let size_pos = 6 + ((*resources.get(6)? as usize + 2) & !1);
+        let size = u32::from_be_bytes(resources.get(size_pos..size_pos + 4)?.try_into().ok()?) as usize;
+        let data = resources.get(size_pos + 4..size_pos + 4 + size)?;
+        if id == IPTC_RESOURCE_ID {
+            return Some(data.to_vec());
+        }
+        resources = resources.get(size_pos + 4 + ((size + 1) & !1)..).unwrap_or_default();
+    }
+    None
+}
+
+
+/// Wrap IPTC data in a Photoshop image resource, as a JPEG APP13 segment holds it.
+fn wrap_iptc(iptc: &[u8]) -> Vec<u8> {
+    let mut app13 = [IPTC_JPEG_PREFIX, b"8BIM", &IPTC_RESOURCE_ID.to_be_bytes(), &[0, 0], &(iptc.len() as u32).to_be_bytes(), iptc].concat();
+    if iptc.len() % 2 == 1 {
+        app13.push(0);
+    }
+    app13
+}
+
+
+fn read_tiff(data: &[u8]) -> Metadata {
+    let entries = match exif::parse(data) {
+        Some((entries, _)) => entries,
+        None => return Metadata::default(),
+    };
+    let blob = |tag| entries.iter().find(|e| e.tag == tag).map(|e| e.data.clone());
+    let picture_entries: Vec<Entry> = entries.iter()
+        .filter(|e| !e.is_image_structure() && ![exif::TAG_XMP, exif::TAG_IPTC, exif::TAG_ICC].contains(&e.tag))
+        .cloned()
+        .collect();
+    Metadata {
+        exif: (!picture_entries.is_empty()).then(|| exif::serialize(&picture_entries)),
+        xmp: blob(exif::TAG_XMP),
+        iptc: blob(exif::TAG_IPTC),
+        icc: blob(exif::TAG_ICC),
+        ..Metadata::default()
+    }
+}
+
+
+/// Read the metadata blocks from an encoded JPEG, PNG, WebP or TIFF file. Anything else has none.
+pub fn read(data: Vec<u8>) -> Metadata {
+    if data.starts_with(b"MM\0\x2a") || data.starts_with(b"II\x2a\0") {
+        return read_tiff(&data);
+    }
+    let container = match DynImage::from_bytes(Bytes::from(data)) {
+        Ok(Some(container)) => container,
+        _ => return Metadata::default(),
+    };
+    let (exif, xmp, iptc) = match &container {
+        DynImage::Jpeg(jpeg) => (
+            container.exif(),
+            jpeg.segments_by_marker(markers::APP1)
+                .find_map(|s| s.contents().strip_prefix(XMP_JPEG_PREFIX).map(|x| x.to_vec())),
+            jpeg.segments_by_marker(markers::APP13).find_map(|s| unwrap_iptc(s.contents())),
+        ),
+        DynImage::Png(png) => (
+            container.exif(),
+            png.chunks_by_type(PNG_CHUNK_ITXT)
+                .find_map(|c| c.contents().strip_prefix(XMP_PNG_PREFIX).map(|x| x.to_vec())),
+            None,
+        ),
+        // The EXIF chunk holds a bare TIFF header, though some writers prefix it like JPEG's APP1.
+        DynImage::WebP(webp) => (
+            webp.chunk_by_id(CHUNK_EXIF).and_then(|c| c.content().data())
+                .map(|exif| if exif.starts_with(b"Exif\0\0") { exif.slice(6..) } else { exif.clone() }),
+            webp.chunk_by_id(CHUNK_XMP).and_then(|c| c.content().data()).map(|x| x.to_vec()),
+            None,
+        ),
+    };
+    Metadata {
+        exif: exif.map(|exif| exif.to_vec()),
+        icc: container.icc_profile().map(|icc| icc.to_vec()),
+        xmp,
+        iptc,
+        ..Metadata::default()
+    }
+}
+
+
+/// Index right after the leading APPn segments, which is where metadata segments belong.
+fn jpeg_metadata_index(jpeg: &Jpeg) -> usize {
+    jpeg.segments()
+        .iter()
+        .position(|s| !(markers::APP0..=markers::APP15).contains(&s.marker()))
+        .unwrap_or(0)
+}
+
+
+/// Whether the image data of a WebP has an alpha channel, which the VP8X chunk must announce.
+fn webp_has_alpha(webp: &WebP) -> bool {
+    let lossless_alpha = webp.chunk_by_id(CHUNK_VP8L)
+        .and_then(|chunk| chunk.content().data())
+        .is_some_and(|data| data.get(4).is_some_and(|b| b & 0b0001_0000 != 0));
+    webp.has_chunk(CHUNK_ALPH) || lossless_alpha
+}
+
+
+/// Rebuild the chunks of a WebP around its image data: a VP8X header with the right feature flags,
+/// then the ICC profile, the image data, EXIF and XMP, in the order the format requires. img-parts
+/// drops the VP8X chunk, and with it the alpha channel, when a WebP has no ICC profile or EXIF.
+/// The canvas size of a WebP. img-parts reads a VP8X chunk's size from the wrong offset, so it's
+/// only used for simple files.
+fn webp_dimensions(webp: &WebP) -> Option<(u32, u32)> {
+    let u24 = |b: &[u8]| u32::from_le_bytes([b[0], b[1], b[2], 0]) + 1;
+    match webp.chunk_by_id(CHUNK_VP8X) {
+        Some(vp8x) => vp8x.content().data()
+            .and_then(|data| data.get(4..10))
+            .map(|size| (u24(&size[..3]), u24(&size[3..]))),
+        None => webp.dimensions(),
+    }
+}
+
+
+fn embed_webp(data: Vec<u8>, metadata: &Metadata) -> Result<Vec<u8>> {
+    let mut webp = WebP::from_bytes(data.into())
+        .map_err(|e| Error::Encode(format!("Failed to parse encoded WebP: {}", e)))?;
+    let (width, height) = webp_dimensions(&webp)
+        .ok_or_else(|| Error::Encode("Encoded WebP has no dimensions".to_string()))?;
+    let blocks = [(CHUNK_ICCP, &metadata.icc, 0b0010_0000), (CHUNK_EXIF, &metadata.exif, 0b0000_1000), (CHUNK_XMP, &metadata.xmp, 0b0000_0100)];
+    let mut flags = blocks.iter().filter(|(_, block, _)| block.is_some()).fold(0, |flags, (_, _, flag)| flags | flag);
+    if webp_has_alpha(&webp) {
+        flags |= 0b0001_0000;
+    }
+    if webp.has_chunk(CHUNK_ANIM) {
+        flags |= 0b0000_0010;
+    }
+    let vp8x = [&[flags, 0, 0, 0][..], &(width - 1).to_le_bytes()[..3], &(height - 1).to_le_bytes()[..3]].concat();
+    let chunk = |id, data: &Vec<u8>| RiffChunk::new(id, RiffContent::Data(data.clone().into()));
+    let image_chunks = webp.chunks().iter()
+        .filter(|c| ![CHUNK_VP8X, CHUNK_ICCP, CHUNK_EXIF, CHUNK_XMP].contains(&c.id()))
+        .cloned()
+        .collect::<Vec<_>>();
+    let chunks = webp.chunks_mut();
+    chunks.clear();
+    chunks.push(chunk(CHUNK_VP8X, &vp8x));
+    chunks.extend(metadata.icc.as_ref().map(|icc| chunk(CHUNK_ICCP, icc)));
+    chunks.extend(image_chunks);
+    chunks.extend(metadata.exif.as_ref().map(|exif| chunk(CHUNK_EXIF, exif)));
+    chunks.extend(metadata.xmp.as_ref().map(|xmp| chunk(CHUNK_XMP, xmp)));
+    Ok(webp.encoder().bytes().to_vec())
+}
+
+
+/// Write the metadata blocks into already encoded image data. Blocks a format can't hold are
+/// dropped, and formats without metadata support are returned unchanged.
+pub fn embed(data: Vec<u8>, format: ImageFormat, metadata: &Metadata) -> Result<Vec<u8>> {
+    if metadata.is_empty() {
+        return Ok(data);
+    }
+    let exif = metadata.exif.clone().map(Bytes::from);
+    let icc = metadata.icc.clone().map(Bytes::from);
+    Ok(match format {
+        ImageFormat::Jpeg => {
+            let mut jpeg = Jpeg::from_bytes(data.into())
+                .map_err(|e| Error::Encode(format!("Failed to parse encoded JPEG: {}", e)))?;
+            jpeg.set_exif(exif);
+            jpeg.set_icc_profile(icc);
+            let index = jpeg_metadata_index(&jpeg);
+            if let Some(iptc) = &metadata.iptc {
+                jpeg.segments_mut().insert(index, JpegSegment::new_with_contents(markers::APP13, wrap_iptc(iptc).into()));
+            }
+            if let Some(xmp) = &metadata.xmp {
+                let contents = [XMP_JPEG_PREFIX, xmp].concat();
+                jpeg.segments_mut().insert(index, JpegSegment::new_with_contents(markers::APP1, contents.into()));
+            }
+            jpeg.encoder().bytes().to_vec()
+        }
+        ImageFormat::Png => {
+            let mut png = Png::from_bytes(data.into())
+                .map_err(|e| Error::Encode(format!("Failed to parse encoded PNG: {}", e)))?;
+            png.set_exif(exif);
+            png.set_icc_profile(icc);
+            if let Some(xmp) = &metadata.xmp {
+                let contents = [XMP_PNG_PREFIX, xmp].concat();
+                let index = png.chunks().len() - 1;
+                png.chunks_mut().insert(index, PngChunk::new(PNG_CHUNK_ITXT, contents.into()));
+            }
+            png.encoder().bytes().to_vec()
+        }
+        ImageFormat::WebP => embed_webp(data, metadata)?,
+        ImageFormat::Tiff => {
+            let mut entries: Vec<Entry> = metadata.exif.as_deref()
+                .and_then(exif::parse)
+                .map(|(entries, _)| entries.into_iter().filter(|e| !e.is_image_structure()).collect())
+                .unwrap_or_default();
+            let blobs = [(exif::TAG_XMP, &metadata.xmp), (exif::TAG_IPTC, &metadata.iptc), (exif::TAG_ICC, &metadata.icc)];
+            for (tag, blob) in blobs {
+                if let Some(blob) = blob {
+                    entries.push(Entry::blob(tag, blob.clone()));
+                }
+            }
+            exif::append_to_tiff(data, entries)
+                .ok_or_else(|| Error::Encode("Failed to parse encoded TIFF".to_string()))?
+        }
+        _ => data,
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::image::{DynamicImage, RgbaImage};
+    use crate::image::encode::encode_image;
+    use crate::image::EncodeOptions;
+
+    fn encode(format: ImageFormat) -> Vec<u8> {
+        let image = DynamicImage::ImageRgba8(RgbaImage::from_fn(4, 4, |x, _| ::image::Rgba([0, 0, 0, x as u8 * 80])));
+        encode_image(image, &Metadata::default(), format, &EncodeOptions::default()).unwrap()
+    }
+
+    #[test]
+    fn embeds_and_reads_back_metadata() {
+        let metadata = Metadata {
+            exif: Some(b"MM\0\x2a\0\0\0\x08\0\x01\x01\x0f\0\x02\0\0\0\x04ACME\0\0\0\0".to_vec()),
+            xmp: Some(b"<x:xmpmeta/>".to_vec()),
+            // A record 2 dataset: the object name "Cat".
+            iptc: Some(b"\x1c\x02\x05\x00\x03Cat".to_vec()),
+            icc: Some(vec![7; 200]),
+            ..Metadata::default()
+        };
+        for format in [ImageFormat::Jpeg, ImageFormat::Png, ImageFormat::Tiff, ImageFormat::WebP] {
+            let data = embed(encode(format), format, &metadata).unwrap();
+            // image-rs can't decode lossy WebPs with an alpha channel, so libwebp checks those.
+            let has_alpha = match format {
+                ImageFormat::WebP => webp::Decoder::new(&data).decode().unwrap().is_alpha(),
+                _ => ::image::load_from_memory_with_format(&data, format).unwrap().color().has_alpha(),
+            };
+            assert_eq!(has_alpha, format != ImageFormat::Jpeg, "{:?}", format);
+            let read = read(data);
+            assert_eq!(read.xmp, metadata.xmp, "{:?}", format);
+            assert_eq!(read.icc, metadata.icc, "{:?}", format);
+            if matches!(format, ImageFormat::Jpeg | ImageFormat::Tiff) {
+                assert_eq!(read.iptc, metadata.iptc, "{:?}", format);
+            }
+            let make = ::exif::Reader::new().read_raw(read.exif.unwrap()).unwrap()
+                .get_field(::exif::Tag::Make, ::exif::In::PRIMARY)
+                .map(|f| f.display_value().to_string());
+            assert_eq!(make.as_deref(), Some("\"ACME\""), "{:?}", format);
+        }
+    }
+
+    #[test]
+    fn strips_gps_from_xmp() {
+        let xmp = br#"<rdf:Description exif:GPSLatitude="52,22.5N" tiff:Make="ACME" exif:GPSLongitude='4,53.9E'>
+            <exif:GPSAltitude>12/1</exif:GPSAltitude><exif:GPSVersionID/><exif:ExposureTime>1/60</exif:ExposureTime>
+        </rdf:Description>"#;
+        let stripped = String::from_utf8(strip_xmp_gps(xmp)).unwrap();
+        assert!(!stripped.contains("GPS"), "{}", stripped);
+        assert!(stripped.contains(r#"tiff:Make="ACME""#) && stripped.contains("<exif:ExposureTime>1/60</exif:ExposureTime>"));
+    }
+}
@@ -1,14 +1,36 @@
-use std::path::{PathBuf};
+use std::fs;
+use std::io::{Read, Seek, Write};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::time::SystemTime;
 use crate::transform::{Resize, Transform};
-use anyhow::Result;
-use ::image::{DynamicImage, ImageFormat};
+use crate::error::{Error, Result};
+use ::image::{DynamicImage, ImageFormat, Rgba};
 use ::image::imageops::FilterType;
-use crate::util::create_path;
+use crate::util::{create_path, fill_image_placeholders, fill_size_placeholders, replace_file, write_atomically, DateTime};
 
 mod pdf;
 mod heif;
 mod image_rs;
+mod exif;
+mod metadata;
+mod icc;
+mod encode;
+mod pages;
+mod color;
+mod canvas;
+mod palette;
+mod quantize;
+
+pub use pdf::set_pdfium_library_path;
+pub use metadata::{Metadata, MetadataKind};
+pub use self::heif::HeifImages;
+pub use icc::ColorProfile;
+pub use encode::EncodeOptions;
+pub use pages::Pages;
+pub use canvas::Pattern;
+pub use palette::{DominantColor, PaletteAlgorithm};
+pub use quantize::{Dither, Palette};
 
 #[derive(Copy, Clone, Debug)]
 pub enum Format {
@@ -17,6 +39,9 @@ pub enum Format {
     Png,
     Jpeg,
     Bmp,
+    Tiff,
+    WebP,
+    Gif,
 }
 
 impl Format {
@@ -27,6 +52,9 @@ impl Format {
             Format::Png => "png",
             Format::Jpeg => "jpg",
             Format::Bmp => "bmp",
+            Format::Tiff => "tiff",
+            Format::WebP => "webp",
+            Format::Gif => "gif",
         }
     }
 }
@@ -41,40 +69,54 @@ impl FromStr for Format {
             "jpeg" | "jpg" => Format::Jpeg,
             "heic" => Format::Heif,
             "bmp" => Format::Bmp,
+            "tif" | "tiff" => Format::Tiff,
+            "webp" => Format::WebP,
+            "gif" => Format::Gif,
             _ => return Err(()),
         })
     }
 }
 
 impl TryInto<ImageFormat> for Format {
-    type Error = anyhow::Error;
+    type Error = Error;
 
-    fn try_into(self) -> std::result::Result<ImageFormat, Self::Error> {
+    fn try_into(self) -> Result<ImageFormat> {
         Ok(match self {
             Format::Png => ImageFormat::Png,
             Format::Jpeg => ImageFormat::Jpeg,
             Format::Bmp => ImageFormat::Bmp,
-            _ => return Err(anyhow::anyhow!("Format unsupported by image-rs library.")),
+            Format::Tiff => ImageFormat::Tiff,
+            Format::WebP => ImageFormat::WebP,
+            Format::Gif => ImageFormat::Gif,
+            _ => return Err(Error::UnsupportedOperation(format!("{} is unsupported by the image-rs library", self.as_str()))),
         })
     }
 }
 
 
-#[allow(unused)]
-pub struct Metadata {
-    width: usize,
-    height: usize,
-}
+/// Side length of a canvas when no dimensions are given.
+const CANVAS_SIZE: u32 = 512;
 
 
 pub enum DataSource {
     File(PathBuf, Format),
     Memory(Vec<u8>, Format),
     Image(DynamicImage),
+    /// A canvas filled with a color, gradient or other pattern. It is sized by the width and height
+    /// options, and only has an alpha channel when one of the pattern's colors isn't opaque.
+    Canvas(Pattern),
 }
 
 
 impl DataSource {
+    /// When the source file was last modified.
+    fn modified(&self) -> Option<SystemTime> {
+        match self {
+            DataSource::File(path, _) => fs::metadata(path).and_then(|m| m.modified()).ok(),
+            _ => None,
+        }
+    }
+
     fn input_file(&self) -> Option<&PathBuf> {
         match self {
             DataSource::File(path, _) => Some(path),
@@ -87,11 +129,32 @@ impl DataSource {
 /// an image can be opened from a file, buffer of pixels (Dynamic Image)
 
 
+/// An output that [`Image::save_every_image`] would write, see [`Image::plan`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct PlannedOutput {
+    /// The output path. `{hash}` and `{date}` are left in, as they depend on the encoded output.
+    pub path: String,
+    /// The size of the output, unless it is only known once rendered, as for PDF pages.
+    pub size: Option<(u32, u32)>,
+}
+
+
 pub struct Image {
     source: DataSource,
-    #[allow(unused)]
+    /// Metadata of a `DataSource::Image`, which has no container to read it from.
     metadata: Option<Metadata>,
 
+    // Decoding options
+    heif_images: HeifImages,
+    auto_orient: bool,
+    color_profile: Option<ColorProfile>,
+
+    // Encoding options
+    keep_metadata: Vec<MetadataKind>,
+    strip_gps: bool,
+    /// Whether the pixels of a `DataSource::Image` were quantized, so PNGs keep a palette.
+    quantized: bool,
+
     // Operations
     resize: Option<Resize>,
     transforms: Vec<Transform>,
@@ -103,6 +166,12 @@ impl Image {
         Self {
             source,
             metadata: None,
+            heif_images: HeifImages::default(),
+            auto_orient: true,
+            color_profile: None,
+            keep_metadata: MetadataKind::ALL.to_vec(),
+            strip_gps: false,
+            quantized: false,
             resize: None,
             transforms: vec![],
         }
@@ -110,22 +179,118 @@ impl Image {
 }
 
 
-fn apply_transforms(mut image: DynamicImage, resize: Option<Resize>, _transforms: Vec<Transform>) -> Result<DynamicImage> {
+/// The size of a canvas. A canvas given only one side is square. Scale and maximums still apply.
+fn canvas_size(resize: Option<Resize>) -> (u32, u32) {
+    match resize {
+        Some(resize) => {
+            let width = resize.width.or(resize.height).map_or(CANVAS_SIZE, |w| w as u32);
+            let height = resize.height.or(resize.width).map_or(CANVAS_SIZE, |h| h as u32);
+            Resize { width: None, height: None, ..resize }.calculate_dimensions(width, height)
+        }
+        None => (CANVAS_SIZE, CANVAS_SIZE),
+    }
+}
+
+/// Whether `transforms` reduce the colors, so PNGs are written with a palette.
+fn quantizes(transforms: &[Transform]) -> bool {
+    transforms.iter().any(|t| matches!(t, Transform::Quantize { .. }))
+}
+
+fn resize_image(image: DynamicImage, resize: Resize) -> DynamicImage {
+    let (width, height) = resize.calculate_dimensions(image.width(), image.height());
+    if (width, height) == (image.width(), image.height()) {
+        return image;
+    }
+    log::debug!("Resizing from {}x{} to {}x{}", image.width(), image.height(), width, height);
+    image.resize(width, height, FilterType::Lanczos3)
+}
+
+/// The size to decode a `width` x `height` page or image at, to resize it from there to each of
+/// `sizes`: the smallest with its aspect ratio that covers them all, so no output is scaled up.
+fn covering_size((width, height): (u32, u32), sizes: &[(u32, u32)]) -> (u32, u32) {
+    let (width, height) = (width.max(1) as f64, height.max(1) as f64);
+    let scale = sizes.iter()
+        .map(|&(w, h)| (w as f64 / width).max(h as f64 / height))
+        .fold(0.0, f64::max);
+    ((width * scale).round() as u32, (height * scale).round() as u32)
+}
+
+/// Resize an image decoded at its [`covering_size`] to one output's `size`: exactly, as pdfium
+/// renders a page given both sides, or else within it, as [`resize_image`] does.
+fn resize_to(image: DynamicImage, (width, height): (u32, u32), exact: bool) -> DynamicImage {
+    if (width, height) == (image.width(), image.height()) {
+        return image;
+    }
+    log::debug!("Resizing from {}x{} to {}x{}", image.width(), image.height(), width, height);
+    if exact {
+        image.resize_exact(width, height, FilterType::Lanczos3)
+    } else {
+        image.resize(width, height, FilterType::Lanczos3)
+    }
+}
+
+fn apply_transforms(mut image: DynamicImage, resize: Option<Resize>, transforms: Vec<Transform>) -> Result<DynamicImage> {
     if let Some(resize) = resize {
-        let (width, height) = resize.calculate_dimensions(image.width(), image.height());
-        image = image.resize(width, height, FilterType::Lanczos3);
+        image = resize_image(image, resize);
+    }
+    for transform in transforms {
+        image = match transform {
+            Transform::Resize(resize) => resize_image(image, resize),
+            Transform::Quantize { palette, dither } => quantize::quantize(image, &palette, dither),
+        };
     }
     Ok(image)
 }
 
+/// The dimensions a `width` x `height` image ends up with after the resizes among `transforms`.
+fn transformed_size(transforms: &[Transform], (width, height): (u32, u32)) -> (u32, u32) {
+    transforms.iter().fold((width, height), |(width, height), transform| match transform {
+        Transform::Resize(resize) => resize.calculate_dimensions(width, height),
+        Transform::Quantize { .. } => (width, height),
+    })
+}
+
+/// Convert the pixels to the `target` ICC profile, which then replaces the source's profile.
+fn convert_color_profile(image: DynamicImage, metadata: Metadata, target: Option<&[u8]>) -> Result<(DynamicImage, Metadata)> {
+    let target = match target {
+        Some(target) => target,
+        None => return Ok((image, metadata)),
+    };
+    let image = icc::convert(image, metadata.icc.as_deref(), target)?;
+    Ok((image, Metadata { icc: Some(target.to_vec()), ..metadata }))
+}
+
+/// When a picture was taken, for `{date}` in output paths: its EXIF date, which is in the camera's
+/// local time, else when the file was last modified, in UTC.
+fn output_date(metadata: &Metadata, modified: Option<SystemTime>) -> Option<DateTime> {
+    metadata.capture_date().or_else(|| modified.map(DateTime::from_system_time))
+}
+
+/// Save `image` along with its metadata, in the format given by the extension of `path`, creating
+/// missing directories. The placeholders for the image are filled in, with `date` defaulting to now.
+/// Returns the path written.
+fn save_image(image: DynamicImage, metadata: &Metadata, path: &str, date: Option<DateTime>, options: &EncodeOptions) -> Result<PathBuf> {
+    let format = ImageFormat::from_path(path)
+        .map_err(|_| Error::UnknownFormat(path.to_string()))?;
+    let (width, height) = (image.width(), image.height());
+    let data = encode::encode_image(image, metadata, format, options)?;
+    let date = date.unwrap_or_else(|| DateTime::from_system_time(SystemTime::now()));
+    let path = fill_image_placeholders(path, width, height, &data, date);
+    if let Some(dir) = Path::new(&path).parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::create_dir_all(dir)?;
+    }
+    let path = PathBuf::from(path);
+    write_atomically(&path, &data)?;
+    log::info!("Wrote {} ({}x{}, {} bytes)", path.display(), width, height, data.len());
+    Ok(path)
+}
+
 impl Image {
     pub fn open<S: Into<PathBuf>>(path: S) -> Result<Self> {
         let path = path.into();
-        let ext = path.extension()
-            .expect("No file extension or file extension unrecognized.")
-            .to_string_lossy();
-        let format = Format::from_str(&ext)
-            .map_err(|_| anyhow::anyhow!("Unknown file extension: {}", ext))?;
+        let format = path.extension()
+            .and_then(|ext| Format::from_str(&ext.to_string_lossy()).ok())
+            .ok_or_else(|| Error::UnknownFormat(path.display().to_string()))?;
         Ok(Self::new(DataSource::File(path, format)))
     }
 
@@ -133,27 +298,99 @@ impl Image {
         Ok(Self::new(DataSource::Memory(data.to_vec(), format)))
     }
 
-    pub fn save(self, path: &str) -> Result<()> {
-        self.to_image()?
-            .to_rgba8()
-            .save(path)
-            .map_err(|e| anyhow::anyhow!("{}", e))
+    /// Read the whole of `reader` as an image in `format`, e.g. from stdin or a request body.
+    pub fn read_from<R: Read>(mut reader: R, format: Format) -> Result<Self> {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        Ok(Self::new(DataSource::Memory(data, format)))
+    }
+
+    /// Save the image in the format given by the extension of `path`. The path may contain
+    /// `{width}`, `{height}`, `{hash}` and `{date}` placeholders, see [`Image::save_every_image`].
+    /// Returns the path written, with the placeholders filled in.
+    pub fn save(self, path: &str) -> Result<PathBuf> {
+        let modified = self.source.modified();
+        let keep_metadata = self.keep_metadata.clone();
+        let strip_gps = self.strip_gps;
+        let options = EncodeOptions::default().indexed(self.quantizes());
+        let (image, metadata) = self.decode()?;
+        let date = output_date(&metadata, modified);
+        save_image(image, &metadata.filter(&keep_metadata, strip_gps), path, date, &options)
     }
 
-    pub fn save_every_image(self, path_template: &str) -> Result<()> {
+    /// Convert the source file and write it back over itself, in its own format. The file is
+    /// replaced atomically and keeps its permissions and modification time. With `backup`, the
+    /// original is kept as `<path>.bak`. PDF and HEIF files, TIFFs with several pages and animated
+    /// GIFs can't be converted in place.
+    pub fn save_in_place(self, backup: bool) -> Result<()> {
+        let (path, format) = match &self.source {
+            DataSource::File(path, format) => (path.clone(), *format),
+            _ => return Err(Error::UnsupportedOperation("Only files can be converted in place".to_string())),
+        };
+        if matches!(format, Format::Pdf | Format::Heif) {
+            return Err(Error::UnsupportedOperation(format!("{} files can't be converted in place", format.as_str())));
+        }
+        // Only the first page would be written back.
+        if matches!(format, Format::Tiff | Format::Gif) && pages::has_several_pages(&fs::read(&path)?, format)? {
+            return Err(Error::UnsupportedOperation(format!("{} files with several pages or frames can't be converted in place", format.as_str())));
+        }
+        let data = self.encode(format, EncodeOptions::default())?;
+        replace_file(&path, &data, backup)?;
+        log::info!("Replaced {} ({} bytes)", path.display(), data.len());
+        Ok(())
+    }
+
+    /// Encode the image in `format` and return the encoded bytes.
+    pub fn encode(self, format: Format, options: EncodeOptions) -> Result<Vec<u8>> {
+        let image_format = format.try_into()?;
+        let options = options.indexed(options.indexed || self.quantizes());
+        let (image, metadata) = self.decode_for_output()?;
+        encode::encode_image(image, &metadata, image_format, &options)
+    }
+
+    /// Encode the image in `format` and write it to `writer`.
+    pub fn write_to<W: Write + Seek>(self, writer: &mut W, format: Format, options: EncodeOptions) -> Result<()> {
+        let data = self.encode(format, options)?;
+        writer.write_all(&data)?;
+        Ok(())
+    }
+
+    /// Save every page or image of the source, see [`Image::pages`], filling in the placeholders of
+    /// `path_template` for each: `{}` (the input file name without extension), `{i}` (the page number),
+    /// `{dir}`, `{filename}`, `{ext}`, `{page_count}`, `{width}`, `{height}`, `{format}` (the output
+    /// extension, which can't be a placeholder itself), `{hash}` (of the encoded output) and `{date}` or `{date:<strftime format>}` (the
+    /// EXIF capture date in the camera's local time, else the input's modification time in UTC).
+    /// Returns the paths written.
+    pub fn save_every_image(self, path_template: &str) -> Result<Vec<PathBuf>> {
+        let modified = self.source.modified();
         match self.source {
             DataSource::File(ref src_path, format) => match format {
                 Format::Pdf => {
-                    let Image { resize, transforms, .. } = self;
+                    let Image { color_profile, resize, transforms, .. } = self;
+                    let target_icc = color_profile.map(|p| p.icc()).transpose()?;
+                    let options = EncodeOptions::default().indexed(quantizes(&transforms));
                     return pdf::transform_all_pages_from_path(
                         &src_path, resize, |i, n_pages, image| {
                             let transforms = transforms.clone();
+                            let (image, metadata) = convert_color_profile(image, Metadata::default(), target_icc.as_deref())?;
                             let image = apply_transforms(image, None, transforms)?;
                             let path = create_path(path_template, &src_path, i, n_pages);
-                            image
-                                .save(&path)
-                                .map_err(|e| anyhow::anyhow!("{}", e))?;
-                            return Ok(())
+                            save_image(image, &metadata, &path, output_date(&metadata, modified), &options)
+                        });
+                }
+                Format::Heif => {
+                    let Image { heif_images, auto_orient, color_profile, keep_metadata, strip_gps, resize, transforms, .. } = self;
+                    let target_icc = color_profile.map(|p| p.icc()).transpose()?;
+                    let options = EncodeOptions::default().indexed(quantizes(&transforms));
+                    return heif::transform_all_images_from_path(
+                        &src_path, heif_images, auto_orient, resize, |i, n_images, image, metadata| {
+                            let transforms = transforms.clone();
+                            let (image, metadata) = convert_color_profile(image, metadata, target_icc.as_deref())?;
+                            let image = apply_transforms(image, None, transforms)?;
+                            let date = output_date(&metadata, modified);
+                            let metadata = metadata.filter(&keep_metadata, strip_gps);
+                            let path = create_path(path_template, &src_path, i, n_images);
+                            save_image(image, &metadata, &path, date, &options)
                         });
                 }
                 _ => {}
@@ -163,39 +400,284 @@ impl Image {
         let path = if let DataSource::File(ref src_path, ..) = self.source {
             create_path(path_template, &src_path, 1, 1)
         } else {
-            "stdin".to_string()
+            create_path(path_template, &PathBuf::new(), 1, 1)
         };
-        self.save(path.as_ref())
+        Ok(vec![self.save(path.as_ref())?])
     }
 
-    pub fn to_image(self) -> Result<DynamicImage> {
-        let Image { source, resize, transforms, .. } = self;
-        let image = match source {
+    /// The outputs [`Image::save_every_image`] would write for `path_template`, found from the
+    /// headers of the source without decoding or writing anything.
+    pub fn plan(&self, path_template: &str) -> Result<Vec<PlannedOutput>> {
+        let resized = |(width, height)| transformed_size(
+            &self.transforms,
+            self.resize.map_or((width, height), |r| r.calculate_dimensions(width, height)),
+        );
+        // Files with several pages or images number them from their index, as in `save_every_image`.
+        let (src_path, sizes, numbered) = match &self.source {
+            DataSource::File(path, Format::Pdf) => (path.clone(), vec![None; pdf::page_count(&fs::read(path)?)?], true),
+            DataSource::File(path, Format::Heif) => {
+                let sizes = heif::item_sizes(&fs::read(path)?, self.heif_images)?;
+                (path.clone(), sizes.into_iter().map(|size| Some(resized(size))).collect(), true)
+            }
+            DataSource::File(path, format) => {
+                let size = image_rs::read_dimensions(fs::read(path)?, *format, self.auto_orient)?;
+                (path.clone(), vec![Some(resized(size))], false)
+            }
+            DataSource::Memory(_, Format::Pdf) => (PathBuf::new(), vec![None], false),
+            DataSource::Memory(data, Format::Heif) => {
+                let size = heif::item_sizes(data, self.heif_images)?[0];
+                (PathBuf::new(), vec![Some(resized(size))], false)
+            }
+            DataSource::Memory(data, format) => {
+                let size = image_rs::read_dimensions(data.clone(), *format, self.auto_orient)?;
+                (PathBuf::new(), vec![Some(resized(size))], false)
+            }
+            DataSource::Image(im) => (PathBuf::new(), vec![Some(resized((im.width(), im.height())))], false),
+            DataSource::Canvas(_) => (PathBuf::new(), vec![Some(transformed_size(&self.transforms, canvas_size(self.resize)))], false),
+        };
+        let n = sizes.len();
+        Ok(sizes.into_iter().enumerate().map(|(i, size)| {
+            let path = if numbered {
+                create_path(path_template, &src_path, i, n)
+            } else {
+                create_path(path_template, &src_path, 1, 1)
+            };
+            let path = match size {
+                Some((width, height)) => fill_size_placeholders(&path, width, height),
+                None => path,
+            };
+            PlannedOutput { path, size }
+        }).collect())
+    }
+
+    /// Save each of `outputs`, an image and a path template, like [`Image::save_every_image`] does,
+    /// but decoding their shared source once: each page is decoded and saved by every output before
+    /// the next one is. The source and decoding options of the first image are used, and only the
+    /// resize, operations and encoding options of the others. PDF pages and HEIF images are decoded
+    /// at the smallest size that covers every output, and scaled down from there. Returns the paths
+    /// written.
+    pub fn save_outputs(outputs: Vec<(Image, String)>) -> Result<Vec<PathBuf>> {
+        let first = match outputs.first() {
+            Some((first, _)) => first,
+            None => return Ok(Vec::new()),
+        };
+        let modified = first.source.modified();
+        let target_icc = first.color_profile.as_ref().map(|p| p.icc()).transpose()?;
+        let mut written = Vec::new();
+        // Each output's size for a page or image of `size`, before its operations.
+        let output_sizes = |(width, height): (u32, u32)| outputs.iter()
+            .map(|(output, _)| output.resize.map_or((width, height), |resize| resize.calculate_dimensions(width, height)))
+            .collect::<Vec<_>>();
+        // Save page `i` of `n`, or the only one if `n` is None, with each output. `sizes` are the
+        // outputs' sizes before their operations, if the page was decoded at its covering size,
+        // and whether to resize to them exactly.
+        let mut save_page = |path: &PathBuf, i: usize, n: Option<usize>, image: DynamicImage, metadata: Metadata, sizes: Option<(&[(u32, u32)], bool)>| -> Result<()> {
+            let (image, metadata) = convert_color_profile(image, metadata, target_icc.as_deref())?;
+            let date = output_date(&metadata, modified);
+            for (k, (output, path_template)) in outputs.iter().enumerate() {
+                let image = match sizes {
+                    Some((sizes, exact)) => apply_transforms(resize_to(image.clone(), sizes[k], exact), None, output.transforms.clone())?,
+                    None => apply_transforms(image.clone(), output.resize, output.transforms.clone())?,
+                };
+                let metadata = Metadata {
+                    width: image.width() as usize,
+                    height: image.height() as usize,
+                    ..metadata.clone().filter(&output.keep_metadata, output.strip_gps)
+                };
+                let path = match n {
+                    Some(n) => create_path(path_template, path, i, n),
+                    None => create_path(path_template, path, 1, 1),
+                };
+                let options = EncodeOptions::default().indexed(output.quantizes());
+                written.push(save_image(image, &metadata, &path, date, &options)?);
+            }
+            Ok(())
+        };
+        match &first.source {
+            DataSource::File(path, Format::Pdf) => {
+                let pdf = pdf::PdfFile::new(fs::read(path)?)?;
+                let n = pdf.page_count();
+                for i in 0..n {
+                    let size = pdf.page_size(i)?;
+                    let sizes = output_sizes(size);
+                    // Pdfium renders at the target size, which keeps vector content sharp.
+                    let (width, height) = covering_size(size, &sizes);
+                    let render = Resize { width: Some(width as usize), height: Some(height as usize), ..Resize::default() };
+                    let page = pdf.render_page(i, Some(render))?;
+                    save_page(path, i, Some(n), page, Metadata::default(), Some((&sizes, true)))?;
+                }
+            }
+            DataSource::File(path, Format::Heif) => {
+                let heif = heif::HeifFile::new(fs::read(path)?, first.heif_images)?;
+                let n = heif.image_count();
+                for i in 0..n {
+                    log::debug!("Decoding image {} of {}", i + 1, n);
+                    let size = heif.image_size(i)?;
+                    let sizes = output_sizes(size);
+                    // Decoding smaller can use an embedded thumbnail instead of the full image.
+                    let (width, height) = covering_size(size, &sizes);
+                    let resize = (width < size.0 && height < size.1)
+                        .then_some(Resize { width: Some(width as usize), height: Some(height as usize), ..Resize::default() });
+                    let (image, metadata) = heif.decode(i, first.auto_orient, resize)?;
+                    save_page(path, i, Some(n), image, metadata, Some((&sizes, false)))?;
+                }
+            }
+            source => {
+                let (path, source) = match source {
+                    DataSource::File(path, format) => (path.clone(), DataSource::File(path.clone(), *format)),
+                    DataSource::Memory(data, format) => (PathBuf::new(), DataSource::Memory(data.clone(), *format)),
+                    DataSource::Image(im) => (PathBuf::new(), DataSource::Image(im.clone())),
+                    DataSource::Canvas(_) => return Err(Error::UnsupportedOperation("A canvas is sized by each output, so it can't be decoded ahead of them".to_string())),
+                };
+                let image = Self {
+                    metadata: first.metadata.clone(),
+                    heif_images: first.heif_images,
+                    auto_orient: first.auto_orient,
+                    ..Self::new(source)
+                };
+                let (image, metadata) = image.decode()?;
+                save_page(&path, 1, None, image, metadata, None)?;
+            }
+        }
+        Ok(written)
+    }
+
+    /// Decode the source and apply the pending operations, keeping the source's metadata.
+    fn decode(self) -> Result<(DynamicImage, Metadata)> {
+        let Image { source, metadata, heif_images, auto_orient, color_profile, mut resize, transforms, .. } = self;
+        // HEIF resizes while decoding, so it can use an embedded thumbnail when one is big enough.
+        let (image, metadata) = match source {
             DataSource::File(path, format) => match format {
-                Format::Pdf => pdf::open_page(&path, 0, None)?,
-                Format::Heif => heif::open_image(&path, None)?,
-                other_format => image_rs::open_image(&path, other_format)?,
+                Format::Pdf => (pdf::open_page(&path, 0, None)?, Metadata::default()),
+                Format::Heif => heif::open_image(&path, heif_images, auto_orient, resize.take())?,
+                other_format => image_rs::open_image(&path, other_format, auto_orient)?,
             }
             DataSource::Memory(data, format) => match format {
-                Format::Pdf => pdf::read_page(&data, 0, None)?,
-                Format::Heif => heif::read_image(&data, None)?,
-                other_format => image_rs::read_image(data, other_format)?,
+                Format::Pdf => (pdf::read_page(&data, 0, None)?, Metadata::default()),
+                Format::Heif => heif::read_image(data, heif_images, auto_orient, resize.take())?,
+                other_format => image_rs::read_image(data, other_format, auto_orient)?,
             },
-            DataSource::Image(im) => im
+            DataSource::Image(im) => (im, metadata.unwrap_or_default()),
+            DataSource::Canvas(pattern) => {
+                let (width, height) = canvas_size(resize.take());
+                (pattern.render(width, height), Metadata::default())
+            }
         };
-        apply_transforms(image, resize, transforms)
+        // Colors are converted first, so quantizing picks its palette in the output's color space.
+        let target_icc = color_profile.map(|p| p.icc()).transpose()?;
+        let (image, metadata) = convert_color_profile(image, metadata, target_icc.as_deref())?;
+        let image = apply_transforms(image, resize, transforms)?;
+        let metadata = Metadata {
+            width: image.width() as usize,
+            height: image.height() as usize,
+            ..metadata
+        };
+        Ok((image, metadata))
+    }
+
+    /// Decode, keeping only the metadata that should be written into the output.
+    fn decode_for_output(self) -> Result<(DynamicImage, Metadata)> {
+        let keep_metadata = self.keep_metadata.clone();
+        let strip_gps = self.strip_gps;
+        let (image, metadata) = self.decode()?;
+        Ok((image, metadata.filter(&keep_metadata, strip_gps)))
+    }
+
+    pub fn to_image(self) -> Result<DynamicImage> {
+        self.decode().map(|(image, _)| image)
     }
 
+    /// Decode the image and return its metadata, with the dimensions after any pending operations.
+    pub fn metadata(self) -> Result<Metadata> {
+        self.decode().map(|(_, metadata)| metadata)
+    }
+
+    /// Decode the image and apply the pending operations. The result keeps the encoding options.
     pub fn apply(self) -> Result<Image> {
-        let im = self.to_image()?;
+        let keep_metadata = self.keep_metadata.clone();
+        let strip_gps = self.strip_gps;
+        let quantized = self.quantizes();
+        let (im, metadata) = self.decode()?;
         Ok(Self {
-            source: DataSource::Image(im),
-            metadata: None,
-            resize: None,
-            transforms: vec![],
+            metadata: Some(metadata),
+            keep_metadata,
+            strip_gps,
+            quantized,
+            ..Self::new(DataSource::Image(im))
         })
     }
 
+    /// Whether the output is quantized, so PNGs are written with a palette.
+    fn quantizes(&self) -> bool {
+        self.quantized || quantizes(&self.transforms)
+    }
+
+    /// A page decoded from this image's source, with the same options and pending operations.
+    /// `resized` says whether the decoder already applied the resize.
+    fn with_page(&self, page: DynamicImage, metadata: Metadata, resized: bool) -> Image {
+        Self {
+            metadata: Some(metadata),
+            heif_images: self.heif_images,
+            auto_orient: self.auto_orient,
+            color_profile: self.color_profile.clone(),
+            keep_metadata: self.keep_metadata.clone(),
+            strip_gps: self.strip_gps,
+            resize: if resized { None } else { self.resize },
+            transforms: self.transforms.clone(),
+            ..Self::new(DataSource::Image(page))
+        }
+    }
+
+    /// Iterate over the pages of a PDF, the images of a HEIF file (see [`Image::heif_images`]),
+    /// the pages of a TIFF or the frames of a GIF. Other formats have a single page. The file is
+    /// parsed once, and a PDF keeps pdfium to itself until the iterator is dropped.
+    pub fn pages(self) -> Result<Pages> {
+        Pages::new(self)
+    }
+
+    /// Open a file and iterate over its pages. See [`Image::pages`].
+    pub fn open_all<S: Into<PathBuf>>(path: S) -> Result<Pages> {
+        Self::open(path)?.pages()
+    }
+
+    /// Rotate images upright according to their EXIF orientation while decoding. On by default.
+    /// Resizing then applies to the upright dimensions.
+    pub fn auto_orient(mut self, auto_orient: bool) -> Self {
+        self.auto_orient = auto_orient;
+        self
+    }
+
+    /// Convert the pixels from their embedded ICC profile (sRGB if there is none) to `profile`,
+    /// which is then embedded in the output.
+    pub fn convert_profile(mut self, profile: ColorProfile) -> Self {
+        self.color_profile = Some(profile);
+        self
+    }
+
+    /// Don't write any metadata blocks (EXIF, XMP, IPTC, ICC) into the output.
+    pub fn strip(mut self, strip: bool) -> Self {
+        self.keep_metadata = if strip { vec![] } else { MetadataKind::ALL.to_vec() };
+        self
+    }
+
+    /// Only write the given kinds of metadata blocks into the output. Everything is kept by default.
+    pub fn keep_metadata(mut self, kinds: &[MetadataKind]) -> Self {
+        self.keep_metadata = kinds.to_vec();
+        self
+    }
+
+    /// Remove GPS location data from the EXIF block written into the output.
+    pub fn strip_gps(mut self, strip_gps: bool) -> Self {
+        self.strip_gps = strip_gps;
+        self
+    }
+
+    /// Choose which images of a HEIF container are decoded. See [`HeifImages`].
+    pub fn heif_images(mut self, selection: HeifImages) -> Self {
+        self.heif_images = selection;
+        self
+    }
+
     pub fn set_width(mut self, width: usize) -> Self {
         let resize = self.resize.get_or_insert(Resize::default());
         resize.width = Some(width);
@@ -226,16 +708,61 @@ impl Image {
         self
     }
 
-    // pub fn dominant_colors(self, n: usize) -> Result<Vec<(u8, u8, u8)>> {
-    //     unimplemented!()
-    //     // let im = self.to_image()?
-    //     //     .to_rgb8();
-    //     // let width = im.width();
-    //     // let height = im.height();
-    //     // let data = im.to_vec();
-    //     // let kmean = kmeans::KMeans::new(data, (width * height) as usize, 3);
-    //     // let result = kmean.kmeans_lloyd(n, 100, KMeans::init_kmeanplusplus, &KMeansConfig::default());
-    //     // assert_eq!(result.centroids.len(), n * 3);
-    //     // Ok(result.centroids.chunks(3).map(|c| (c[0], c[1], c[2])).collect())
-    // }
+    /// Resize after the operations added so far. [`Image::set_width`] and the like resize before
+    /// any of them, however late they're called.
+    pub fn resize(mut self, resize: Resize) -> Self {
+        self.transforms.push(Transform::Resize(resize));
+        self
+    }
+
+    /// Reduce the image to a palette, e.g. for GIF or paletted PNG output, or an e-ink display.
+    pub fn quantize(mut self, palette: Palette, dither: Dither) -> Self {
+        self.transforms.push(Transform::Quantize { palette, dither });
+        self
+    }
+
+    /// The `n` dominant colors of the image, with their share of its pixels, most common first.
+    pub fn dominant_colors(self, n: usize, algorithm: PaletteAlgorithm) -> Result<Vec<DominantColor>> {
+        let image = self.to_image()?;
+        Ok(palette::dominant_colors(&image, n, algorithm))
+    }
+
+    /// A canvas of side-by-side stripes of `colors`, each as wide as its share.
+    pub fn swatch(colors: &[DominantColor]) -> Self {
+        let mut position = 0.0;
+        let stops = colors.iter().flat_map(|c| {
+            let start = position;
+            position += c.share;
+            let color = Rgba([c.color[0], c.color[1], c.color[2], u8::MAX]);
+            [(color, start), (color, position)]
+        }).collect();
+        Self::new(DataSource::Canvas(Pattern::LinearGradient { angle: 90.0, stops }))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes_pages_for_every_output() {
+        // A letter page, for an output squeezed to 200x50 and a thumbnail 150 wide.
+        let page = (612, 792);
+        let resizes = [
+            Resize { width: Some(200), height: Some(50), ..Resize::default() },
+            Resize { width: Some(150), ..Resize::default() },
+        ];
+        let sizes = resizes.iter().map(|resize| resize.calculate_dimensions(page.0, page.1)).collect::<Vec<_>>();
+        assert_eq!(sizes, [(200, 50), (150, 194)]);
+        let render = covering_size(page, &sizes);
+        assert_eq!(render, (200, 259));
+
+        let rendered = DynamicImage::new_rgb8(render.0, render.1);
+        let outputs = sizes.iter().map(|&size| {
+            let image = resize_to(rendered.clone(), size, true);
+            (image.width(), image.height())
+        }).collect::<Vec<_>>();
+        assert_eq!(outputs, sizes);
+    }
 }
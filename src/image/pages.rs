@@ -0,0 +1,210 @@
+use std::fs;
+use std::io::Cursor;
+use std::mem;
+use ::image::{AnimationDecoder, DynamicImage, Frames, ImageBuffer};
+use ::image::codecs::gif::GifDecoder;
+use tiff::ColorType;
+use tiff::decoder::{Decoder as TiffDecoder, DecodingResult};
+use crate::error::{Error, Result};
+use crate::image::{heif, metadata, pdf, DataSource, Format, Image, Metadata};
+
+
+enum PageReader {
+    /// Pages are rendered one at a time from the document, which stays loaded.
+    Pdf(pdf::PdfFile),
+    Heif(heif::HeifFile),
+    Tiff { decoder: Box<TiffDecoder<Cursor<Vec<u8>>>>, metadata: Option<Metadata>, done: bool },
+    Gif(Frames<'static>),
+    /// Formats with a single image are their own only page.
+    Single,
+}
+
+
+/// A lazy iterator over the pages of a document, or the images of a multi-image file. Each page is
+/// decoded when the iterator reaches it, and yielded as an [`Image`] with the pending operations applied.
+pub struct Pages {
+    /// The image the pages come from, whose options apply to each page. A single page source is
+    /// taken as its only page.
+    image: Option<Image>,
+    reader: PageReader,
+    index: usize,
+}
+
+
+fn tiff_error(e: tiff::TiffError) -> Error {
+    Error::Decode(e.to_string())
+}
+
+
+fn tiff_page(decoder: &mut TiffDecoder<Cursor<Vec<u8>>>) -> Result<DynamicImage> {
+    let (width, height) = decoder.dimensions().map_err(tiff_error)?;
+    let color = decoder.colortype().map_err(tiff_error)?;
+    let image = match (color, decoder.read_image().map_err(tiff_error)?) {
+        (ColorType::Gray(8), DecodingResult::U8(data)) => ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageLuma8),
+        (ColorType::GrayA(8), DecodingResult::U8(data)) => ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageLumaA8),
+        (ColorType::RGB(8), DecodingResult::U8(data)) => ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageRgb8),
+        (ColorType::RGBA(8), DecodingResult::U8(data)) => ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageRgba8),
+        (ColorType::Gray(16), DecodingResult::U16(data)) => ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageLuma16),
+        (ColorType::GrayA(16), DecodingResult::U16(data)) => ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageLumaA16),
+        (ColorType::RGB(16), DecodingResult::U16(data)) => ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageRgb16),
+        (ColorType::RGBA(16), DecodingResult::U16(data)) => ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageRgba16),
+        (color, _) => return Err(Error::UnsupportedOperation(format!("TIFF pages with color type {:?}", color))),
+    };
+    image.ok_or_else(|| Error::Decode("Failed to create image buffer".to_string()))
+}
+
+
+/// Whether a TIFF has more than one page, or a GIF more than one frame, reading no further than
+/// the second. Other formats have a single page here.
+pub(super) fn has_several_pages(data: &[u8], format: Format) -> Result<bool> {
+    Ok(match format {
+        Format::Tiff => TiffDecoder::new(Cursor::new(data)).map_err(tiff_error)?.more_images(),
+        Format::Gif => GifDecoder::new(Cursor::new(data))?.into_frames().take(2).count() > 1,
+        _ => false,
+    })
+}
+
+
+impl Pages {
+    pub(super) fn new(mut image: Image) -> Result<Self> {
+        let (data, format) = match &mut image.source {
+            DataSource::File(path, format @ (Format::Pdf | Format::Heif | Format::Tiff | Format::Gif)) => (fs::read(path)?, *format),
+            DataSource::Memory(data, format @ (Format::Pdf | Format::Heif | Format::Tiff | Format::Gif)) => (mem::take(data), *format),
+            _ => return Ok(Self { image: Some(image), reader: PageReader::Single, index: 0 }),
+        };
+        let reader = match format {
+            Format::Pdf => PageReader::Pdf(pdf::PdfFile::new(data)?),
+            Format::Heif => PageReader::Heif(heif::HeifFile::new(data, image.heif_images)?),
+            Format::Tiff => PageReader::Tiff {
+                metadata: Some(metadata::read(data.clone())),
+                decoder: Box::new(TiffDecoder::new(Cursor::new(data)).map_err(tiff_error)?),
+                done: false,
+            },
+            _ => PageReader::Gif(GifDecoder::new(Cursor::new(data))?.into_frames()),
+        };
+        Ok(Self { image: Some(image), reader, index: 0 })
+    }
+
+    /// Decode the next page, without the pending operations applied yet.
+    fn next_page(&mut self) -> Option<Result<Image>> {
+        let index = self.index;
+        if let PageReader::Single = self.reader {
+            return self.image.take().map(Ok);
+        }
+        let image = self.image.as_ref()?;
+        let page = match &mut self.reader {
+            PageReader::Single => return None,
+            PageReader::Pdf(pdf) => {
+                if index >= pdf.page_count() {
+                    return None;
+                }
+                // Pdfium renders at the target size, which keeps vector content sharp.
+                pdf.render_page(index, image.resize)
+                    .map(|page| image.with_page(page, Metadata::default(), true))
+            }
+            PageReader::Heif(heif) => {
+                if index >= heif.image_count() {
+                    return None;
+                }
+                heif.decode(index, image.auto_orient, image.resize)
+                    .map(|(page, metadata)| image.with_page(page, metadata, true))
+            }
+            PageReader::Tiff { decoder, metadata, done } => {
+                if *done {
+                    return None;
+                }
+                let page = tiff_page(decoder);
+                if decoder.more_images() {
+                    if let Err(e) = decoder.next_image() {
+                        *done = true;
+                        return Some(Err(tiff_error(e)));
+                    }
+                } else {
+                    *done = true;
+                }
+                page.map(|page| image.with_page(page, metadata.take().unwrap_or_default(), false))
+            }
+            PageReader::Gif(frames) => {
+                let frame = frames.next()?;
+                frame
+                    .map(|frame| image.with_page(DynamicImage::ImageRgba8(frame.into_buffer()), Metadata::default(), false))
+                    .map_err(Error::from)
+            }
+        };
+        Some(page)
+    }
+}
+
+
+impl Iterator for Pages {
+    type Item = Result<Image>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let page = self.next_page()?;
+        self.index += 1;
+        Some(page.and_then(Image::apply))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        match &self.reader {
+            PageReader::Pdf(pdf) => {
+                let remaining = pdf.page_count().saturating_sub(self.index);
+                (remaining, Some(remaining))
+            }
+            PageReader::Heif(heif) => {
+                let remaining = heif.image_count().saturating_sub(self.index);
+                (remaining, Some(remaining))
+            }
+            PageReader::Single => (self.image.is_some() as usize, Some(self.image.is_some() as usize)),
+            _ => (0, None),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::image::{Frame, RgbaImage};
+    use ::image::codecs::gif::GifEncoder;
+    use tiff::encoder::{colortype, TiffEncoder};
+
+    #[test]
+    fn iterates_tiff_pages() {
+        let mut data = Cursor::new(Vec::new());
+        let mut encoder = TiffEncoder::new(&mut data).unwrap();
+        encoder.write_image::<colortype::RGB8>(4, 2, &[10; 24]).unwrap();
+        encoder.write_image::<colortype::Gray16>(2, 2, &[1000; 4]).unwrap();
+        let pages = Image::read(data.get_ref(), Format::Tiff).unwrap()
+            .scale(2.0)
+            .pages().unwrap()
+            .map(|page| page.and_then(Image::to_image).unwrap())
+            .map(|page| (page.width(), page.height()))
+            .collect::<Vec<_>>();
+        assert_eq!(pages, vec![(8, 4), (4, 4)]);
+    }
+
+    #[test]
+    fn counts_several_pages() {
+        for pages in [1, 2] {
+            let mut data = Cursor::new(Vec::new());
+            let mut encoder = TiffEncoder::new(&mut data).unwrap();
+            for _ in 0..pages {
+                encoder.write_image::<colortype::RGB8>(1, 1, &[0; 3]).unwrap();
+            }
+            assert_eq!(has_several_pages(data.get_ref(), Format::Tiff).unwrap(), pages > 1);
+        }
+    }
+
+    #[test]
+    fn iterates_gif_frames() {
+        let mut data = Vec::new();
+        let frames = (0..3).map(|i| Frame::new(RgbaImage::from_pixel(3, 3, ::image::Rgba([i * 80, 0, 0, 255]))));
+        GifEncoder::new(&mut data).encode_frames(frames).unwrap();
+        let reds = Image::read(&data, Format::Gif).unwrap()
+            .pages().unwrap()
+            .map(|page| page.and_then(Image::to_image).unwrap().to_rgba8().get_pixel(1, 1)[0])
+            .collect::<Vec<_>>();
+        assert_eq!(reds, vec![0, 80, 160]);
+    }
+}
@@ -0,0 +1,161 @@
+use std::cmp::Reverse;
+use std::str::FromStr;
+use ::image::{DynamicImage, GenericImageView, Rgb};
+use ::image::imageops::FilterType;
+use kmeans::{KMeans, KMeansConfig};
+
+
+/// Images are downsampled to fit in a square this size before their colors are clustered.
+/// The palette barely changes, and clustering gets much faster.
+const SAMPLE_SIZE: u32 = 128;
+
+
+/// How the dominant colors of an image are found.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum PaletteAlgorithm {
+    /// Cluster the pixels with k-means. Slower, and finds the colors that represent the image best.
+    KMeans,
+    /// Repeatedly split the pixels at the median of their widest channel. Fast and deterministic.
+    MedianCut,
+}
+
+
+impl FromStr for PaletteAlgorithm {
+    type Err = ();
+    fn from_str(input: &str) -> Result<PaletteAlgorithm, Self::Err> {
+        Ok(match input.to_lowercase().as_str() {
+            "kmeans" | "k-means" => PaletteAlgorithm::KMeans,
+            "median-cut" | "mediancut" => PaletteAlgorithm::MedianCut,
+            _ => return Err(()),
+        })
+    }
+}
+
+
+/// One of the dominant colors of an image, with the share of its pixels closest to that color.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct DominantColor {
+    pub color: Rgb<u8>,
+    /// From 0 to 1. The shares of a palette add up to 1.
+    pub share: f32,
+}
+
+
+impl DominantColor {
+    pub fn hex(&self) -> String {
+        format!("#{:02x}{:02x}{:02x}", self.color[0], self.color[1], self.color[2])
+    }
+}
+
+
+/// The pixels of the image, downsampled, leaving out the ones that are mostly transparent.
+fn sample_pixels(image: &DynamicImage) -> Vec<[u8; 3]> {
+    let (width, height) = image.dimensions();
+    let sample = if width > SAMPLE_SIZE || height > SAMPLE_SIZE {
+        image.resize(SAMPLE_SIZE, SAMPLE_SIZE, FilterType::Triangle).to_rgba8()
+    } else {
+        image.to_rgba8()
+    };
+    sample.pixels()
+        .filter(|p| p[3] >= 128)
+        .map(|p| [p[0], p[1], p[2]])
+        .collect()
+}
+
+
+fn widest_channel(pixels: &[[u8; 3]]) -> (usize, u8) {
+    (0..3).map(|channel| {
+        let min = pixels.iter().map(|p| p[channel]).min().unwrap_or(0);
+        let max = pixels.iter().map(|p| p[channel]).max().unwrap_or(0);
+        (channel, max - min)
+    }).max_by_key(|&(_, range)| range).unwrap_or((0, 0))
+}
+
+
+fn average(pixels: &[[u8; 3]]) -> Rgb<u8> {
+    let mut sum = [0u64; 3];
+    for pixel in pixels {
+        for (sum, &c) in sum.iter_mut().zip(pixel) {
+            *sum += c as u64;
+        }
+    }
+    let n = pixels.len().max(1) as u64;
+    Rgb(sum.map(|s| ((s + n / 2) / n) as u8))
+}
+
+
+fn median_cut(pixels: Vec<[u8; 3]>, n: usize) -> Vec<(Rgb<u8>, usize)> {
+    let mut boxes = vec![pixels];
+    while boxes.len() < n {
+        let (index, (channel, range)) = boxes.iter()
+            .map(|b| widest_channel(b))
+            .enumerate()
+            .max_by_key(|&(_, (_, range))| range)
+            .unwrap();
+        if range == 0 {
+            break;
+        }
+        let mut lower = boxes.swap_remove(index);
+        lower.sort_unstable_by_key(|p| p[channel]);
+        // Keep pixels of the same value on one side, so no two boxes end up with the same color.
+        let median = lower[lower.len() / 2][channel];
+        let mut split = lower.partition_point(|p| p[channel] < median);
+        if split == 0 {
+            split = lower.partition_point(|p| p[channel] <= median);
+        }
+        let upper = lower.split_off(split);
+        boxes.push(lower);
+        boxes.push(upper);
+    }
+    boxes.iter().map(|b| (average(b), b.len())).collect()
+}
+
+
+fn k_means(pixels: Vec<[u8; 3]>, n: usize) -> Vec<(Rgb<u8>, usize)> {
+    let count = pixels.len();
+    let samples = pixels.iter().flat_map(|p| p.map(|c| c as f32)).collect();
+    let result = KMeans::new(samples, count, 3)
+        .kmeans_lloyd(n.min(count), 100, KMeans::init_kmeanplusplus, &KMeansConfig::default());
+    result.centroids.chunks(3)
+        .zip(result.centroid_frequency)
+        .filter(|&(_, frequency)| frequency > 0)
+        .map(|(c, frequency)| (Rgb([c[0], c[1], c[2]].map(|c| c.round().clamp(0.0, 255.0) as u8)), frequency))
+        .collect()
+}
+
+
+/// The `n` dominant colors of the image, most common first. Fewer are returned if the image
+/// doesn't have that many distinct colors, and none if it is entirely transparent.
+pub fn dominant_colors(image: &DynamicImage, n: usize, algorithm: PaletteAlgorithm) -> Vec<DominantColor> {
+    let pixels = sample_pixels(image);
+    if pixels.is_empty() || n == 0 {
+        return Vec::new();
+    }
+    let total = pixels.len() as f32;
+    let mut colors = match algorithm {
+        PaletteAlgorithm::KMeans => k_means(pixels, n),
+        PaletteAlgorithm::MedianCut => median_cut(pixels, n),
+    };
+    colors.sort_by_key(|&(_, count)| Reverse(count));
+    colors.into_iter()
+        .map(|(color, count)| DominantColor { color, share: count as f32 / total })
+        .collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::image::RgbImage;
+
+    #[test]
+    fn median_cut_finds_colors_and_shares() {
+        let image = RgbImage::from_fn(8, 8, |x, _| if x < 6 { Rgb([200, 30, 30]) } else { Rgb([20, 20, 220]) });
+        let colors = dominant_colors(&DynamicImage::ImageRgb8(image), 5, PaletteAlgorithm::MedianCut);
+        assert_eq!(colors, vec![
+            DominantColor { color: Rgb([200, 30, 30]), share: 0.75 },
+            DominantColor { color: Rgb([20, 20, 220]), share: 0.25 },
+        ]);
+        assert_eq!(colors[0].hex(), "#c81e1e");
+    }
+}
@@ -2,20 +2,88 @@
 /// PDFs can still contain vector graphics, so it can up-scale them much more smoothly.
 /// If you try to do up-scaling using imcon/image-rs library, you'll have blurry results,
 /// which is not good.
-use std::path::PathBuf;
+use std::env;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+use once_cell::sync::OnceCell;
 use pdfium_render::bitmap_config::PdfBitmapConfig;
 use pdfium_render::pdfium::Pdfium;
 use crate::transform::{Resize};
-use anyhow::Result;
+use crate::error::{Error, Result};
 use ::image::{DynamicImage};
 use pdfium_render::pages::{PdfPageIndex};
 use pdfium_render::document::PdfDocument;
+use self_cell::self_cell;
 
 
-fn make_library_binding() -> Pdfium {
+/// Environment variable pointing at the pdfium library, or a directory containing it.
+const PDFIUM_PATH_ENV: &str = "IMCON_PDFIUM_PATH";
+
+static PDFIUM_LIBRARY_PATH: OnceCell<PathBuf> = OnceCell::new();
+
+/// Pdfium isn't thread safe, so only one thread may have it bound at a time.
+static PDFIUM_LOCK: Mutex<()> = Mutex::new(());
+
+
+/// Use the pdfium library at `path` (a file, or a directory containing it) instead of
+/// searching for one. Takes precedence over `IMCON_PDFIUM_PATH`. Only the first call has an effect.
+pub fn set_pdfium_library_path<P: Into<PathBuf>>(path: P) {
+    let _ = PDFIUM_LIBRARY_PATH.set(path.into());
+}
+
+
+/// If `path` is a directory, point it at the platform library name inside it.
+fn library_file(path: PathBuf) -> PathBuf {
+    if path.is_dir() {
+        path.join(Pdfium::pdfium_platform_library_name())
+    } else {
+        path
+    }
+}
+
+
+/// The library location given explicitly, by the configured path or else the `IMCON_PDFIUM_PATH`
+/// environment variable.
+fn explicit_library_path() -> Option<PathBuf> {
+    PDFIUM_LIBRARY_PATH.get().cloned()
+        .or_else(|| env::var_os(PDFIUM_PATH_ENV).filter(|p| !p.is_empty()).map(PathBuf::from))
+        .map(library_file)
+}
+
+
+/// Wait for other threads to be done with pdfium. Hold the guard until the binding is dropped.
+fn lock_library() -> MutexGuard<'static, ()> {
+    PDFIUM_LOCK.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+
+fn bind_to_library(path: &Path) -> Result<Pdfium> {
+    Pdfium::bind_to_library(path.to_string_lossy())
+        .map(Pdfium::new)
+        .map_err(|e| Error::PdfiumUnavailable(format!("Failed to bind to library at {}: {:?}", path.display(), e)))
+}
+
+
+/// Bind to the library given explicitly, which must exist, else the one next to the running
+/// executable, else the system library.
+fn make_library_binding() -> Result<Pdfium> {
+    if let Some(path) = explicit_library_path() {
+        if !path.exists() {
+            return Err(Error::PdfiumUnavailable(format!("No pdfium library at {}", path.display())));
+        }
+        return bind_to_library(&path);
+    }
+    let next_to_exe = env::current_exe().ok()
+        .and_then(|exe| exe.parent().map(|dir| dir.join(Pdfium::pdfium_platform_library_name())));
+    if let Some(path) = next_to_exe.as_ref().filter(|path| path.exists()) {
+        return bind_to_library(path);
+    }
     let bind = Pdfium::bind_to_system_library()
-        .expect("Failed to bind to Pdfium system library");
-    Pdfium::new(bind)
+        .map_err(|e| Error::PdfiumUnavailable(format!(
+            "Failed to bind to system library: {:?}. Set {} or pass --pdfium-lib to use a bundled copy, or put it next to the executable.",
+            e, PDFIUM_PATH_ENV,
+        )))?;
+    Ok(Pdfium::new(bind))
 }
 
 
@@ -44,49 +112,113 @@ impl Into<PdfBitmapConfig> for Resize {
 
 fn get_page_as_image(doc: &PdfDocument, i: PdfPageIndex, config: PdfBitmapConfig) -> Result<DynamicImage> {
     let pages = doc.pages();
-    let page = pages.get(i as PdfPageIndex)
-        .map_err(|_e| anyhow::anyhow!("Page out of bounds"))?;
-    let mut bitmap = page.get_bitmap_with_config(&config)
-        .map_err(|e| anyhow::anyhow!("Failed to get bitmap: {:?}", e))?;
-    Ok(bitmap.as_image())
+    if i >= pages.len() {
+        return Err(Error::PageOutOfRange { page: i as usize, pages: pages.len() as usize });
+    }
+    let page = pages.get(i)?;
+    let mut bitmap = page.get_bitmap_with_config(&config)?;
+    let image = bitmap.as_image();
+    log::debug!("Rendered page {} of {} at {}x{}", i + 1, pages.len(), image.width(), image.height());
+    Ok(image)
+}
+
+/// What a loaded document borrows from. Fields drop in order, so pdfium is unbound before the lock
+/// is released.
+struct PdfOwner {
+    pdfium: Pdfium,
+    data: Vec<u8>,
+    _lock: MutexGuard<'static, ()>,
 }
 
+self_cell!(
+    struct LoadedPdf {
+        owner: PdfOwner,
+        #[not_covariant]
+        dependent: PdfDocument,
+    }
+);
+
+
+/// A PDF loaded once, to render its pages one at a time. Pdfium stays bound until it is dropped,
+/// so other threads wait for it meanwhile, and the same thread must not use pdfium otherwise.
+pub struct PdfFile(LoadedPdf);
+
+impl PdfFile {
+    pub fn new(data: Vec<u8>) -> Result<Self> {
+        let _lock = lock_library();
+        let pdfium = make_library_binding()?;
+        let loaded = LoadedPdf::try_new(PdfOwner { pdfium, data, _lock }, |owner| {
+            owner.pdfium.load_pdf_from_bytes(&owner.data, None)
+        })?;
+        Ok(Self(loaded))
+    }
+
+    pub fn page_count(&self) -> usize {
+        self.0.with_dependent(|_, doc| doc.pages().len() as usize)
+    }
+
+    /// The size page `i` is rendered at without a resize: its size in points.
+    pub fn page_size(&self, i: usize) -> Result<(u32, u32)> {
+        self.0.with_dependent(|_, doc| {
+            let pages = doc.pages();
+            let page = pages.get(i as PdfPageIndex)?;
+            Ok((page.width().value as u32, page.height().value as u32))
+        })
+    }
+
+    pub fn render_page(&self, i: usize, resize: Option<Resize>) -> Result<DynamicImage> {
+        let config = resize.map(|r| r.into()).unwrap_or_default();
+        self.0.with_dependent(|_, doc| get_page_as_image(doc, i as PdfPageIndex, config))
+    }
+}
+
+
 pub fn open_page(path: &PathBuf, i: usize, resize: Option<Resize>) -> Result<DynamicImage> {
     let config = resize.map(|r| r.into()).unwrap_or_default();
-    let pdfium = make_library_binding();
+    let _lock = lock_library();
+    let pdfium = make_library_binding()?;
     if !path.exists() {
-        return Err(anyhow::anyhow!("File not found: {}", path.display()));
+        return Err(Error::Io(std::io::Error::new(std::io::ErrorKind::NotFound, format!("File not found: {}", path.display()))));
     }
-    let doc = pdfium.load_pdf_from_file(path.to_string_lossy().as_ref(), None)
-        .map_err(|e| anyhow::anyhow!("Failed to load PDF document: {:?}", e))?;
+    let doc = pdfium.load_pdf_from_file(path.to_string_lossy().as_ref(), None)?;
     get_page_as_image(&doc, i as PdfPageIndex, config)
 }
 
 
 pub fn read_page(data: &[u8], i: usize, resize: Option<Resize>) -> Result<DynamicImage> {
     let config = resize.map(|r| r.into()).unwrap_or_default();
-    let pdfium = make_library_binding();
-    let doc = pdfium.load_pdf_from_bytes(data, None)
-        .map_err(|e| anyhow::anyhow!("Failed to load PDF document: {:?}", e))?;
+    let _lock = lock_library();
+    let pdfium = make_library_binding()?;
+    let doc = pdfium.load_pdf_from_bytes(data, None)?;
     get_page_as_image(&doc, i as PdfPageIndex, config)
 }
 
 
-pub fn transform_all_pages_from_path<S>(path: &PathBuf, resize: Option<Resize>, transform: S) -> Result<()>
+pub fn page_count(data: &[u8]) -> Result<usize> {
+    let _lock = lock_library();
+    let pdfium = make_library_binding()?;
+    let doc = pdfium.load_pdf_from_bytes(data, None)?;
+    let count = doc.pages().len() as usize;
+    Ok(count)
+}
+
+
+pub fn transform_all_pages_from_path<S, T>(path: &PathBuf, resize: Option<Resize>, transform: S) -> Result<Vec<T>>
     where
-        S: Fn(usize, usize, DynamicImage) -> Result<()>
+        S: Fn(usize, usize, DynamicImage) -> Result<T>
 {
     let config: PdfBitmapConfig = resize.map(|r| r.into()).unwrap_or_default();
-    let pdfium = make_library_binding();
-    let doc = pdfium.load_pdf_from_file(path.to_string_lossy().as_ref(), None)
-        .map_err(|e| anyhow::anyhow!("Failed to load PDF document: {:?}", e))?;
+    let _lock = lock_library();
+    let pdfium = make_library_binding()?;
+    let doc = pdfium.load_pdf_from_file(path.to_string_lossy().as_ref(), None)?;
     let pages = doc.pages();
     let num_pages = pages.len();
+    let mut results = Vec::new();
     for (i, page) in pages.iter().enumerate() {
-        let mut bmp = page.get_bitmap_with_config(&config)
-            .map_err(|e| anyhow::anyhow!("Failed to get bitmap: {:?}", e))?;
+        let mut bmp = page.get_bitmap_with_config(&config)?;
         let image = bmp.as_image();
-        transform(i, num_pages as usize, image)?;
+        log::debug!("Rendered page {} of {} at {}x{}", i + 1, num_pages, image.width(), image.height());
+        results.push(transform(i, num_pages as usize, image)?);
     }
-    Ok(())
+    Ok(results)
 }
\ No newline at end of file
@@ -0,0 +1,187 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+use ::image::{DynamicImage, Rgb, Rgba};
+use crate::error::{Error, Result};
+use crate::image::color::parse_color;
+use crate::image::palette::{dominant_colors, PaletteAlgorithm};
+
+
+/// The colors an image is reduced to.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Palette {
+    /// The best colors for the image, at most this many, up to 256.
+    Colors(usize),
+    /// Exactly these colors, e.g. what a display can show.
+    Fixed(Vec<Rgb<u8>>),
+}
+
+
+/// How to spread the difference between a pixel and its nearest palette color.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Dither {
+    /// Every pixel becomes its nearest color. Gives flat areas, and bands in gradients.
+    None,
+    /// Diffuse the error onto the neighbouring pixels.
+    FloydSteinberg,
+    /// Offset pixels by a Bayer matrix, which gives a regular crosshatch that compresses well.
+    Ordered,
+}
+
+
+impl FromStr for Dither {
+    type Err = ();
+    fn from_str(input: &str) -> Result<Dither, Self::Err> {
+        Ok(match input.to_lowercase().as_str() {
+            "none" => Dither::None,
+            "floyd-steinberg" | "fs" => Dither::FloydSteinberg,
+            "ordered" | "bayer" => Dither::Ordered,
+            _ => return Err(()),
+        })
+    }
+}
+
+
+impl Palette {
+    /// Read a palette file with one color per line, either as a CSS color or as `R G B` like in
+    /// GIMP palettes. Empty lines, comments and GIMP's header lines are skipped.
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<Palette> {
+        let path = path.as_ref();
+        let mut colors = Vec::new();
+        for line in fs::read_to_string(path)?.lines() {
+            let line = line.trim();
+            if line.is_empty() || line == "GIMP Palette" || line.starts_with("Name:") || line.starts_with("Columns:") {
+                continue;
+            }
+            let channels = line.split_whitespace().take(3).map(u8::from_str).collect::<Result<Vec<_>, _>>();
+            let color = match channels {
+                Ok(channels) if channels.len() == 3 => Rgba([channels[0], channels[1], channels[2], u8::MAX]),
+                // A hex color may be followed by a name. Otherwise '#' starts a comment.
+                _ => match parse_color(line).or_else(|e| parse_color(line.split_whitespace().next().unwrap_or(line)).map_err(|_| e)) {
+                    Ok(color) => color,
+                    Err(_) if line.starts_with('#') => continue,
+                    Err(e) => return Err(e),
+                },
+            };
+            colors.push(Rgb([color[0], color[1], color[2]]));
+        }
+        if colors.is_empty() || colors.len() > 256 {
+            return Err(Error::InvalidColor(format!("{} has {} colors, a palette needs 1 to 256", path.display(), colors.len())));
+        }
+        Ok(Palette::Fixed(colors))
+    }
+}
+
+
+const BAYER: [[u8; 8]; 8] = [
+    [0, 32, 8, 40, 2, 34, 10, 42],
+    [48, 16, 56, 24, 50, 18, 58, 26],
+    [12, 44, 4, 36, 14, 46, 6, 38],
+    [60, 28, 52, 20, 62, 30, 54, 22],
+    [3, 35, 11, 43, 1, 33, 9, 41],
+    [51, 19, 59, 27, 49, 17, 57, 25],
+    [15, 47, 7, 39, 13, 45, 5, 37],
+    [63, 31, 55, 23, 61, 29, 53, 21],
+];
+
+
+/// Finds the closest palette color, remembering colors it has seen.
+struct Nearest<'a> {
+    colors: &'a [Rgb<u8>],
+    cache: HashMap<[u8; 3], Rgb<u8>>,
+}
+
+
+impl<'a> Nearest<'a> {
+    fn find(&mut self, target: [u8; 3]) -> Rgb<u8> {
+        let colors = self.colors;
+        *self.cache.entry(target).or_insert_with(|| {
+            *colors.iter().min_by_key(|c| {
+                (0..3).map(|i| (c[i] as i32 - target[i] as i32).pow(2)).sum::<i32>()
+            }).unwrap()
+        })
+    }
+}
+
+
+/// Reduce the image to the colors of `palette`. Pixels that are mostly transparent become fully
+/// transparent and the others opaque, as GIF and most paletted outputs can't do better anyway.
+pub fn quantize(image: DynamicImage, palette: &Palette, dither: Dither) -> DynamicImage {
+    let has_alpha = image.color().has_alpha();
+    let mut rgba = image.to_rgba8();
+    let colors = match palette {
+        Palette::Colors(n) => {
+            // Leave room for the transparent color.
+            let transparent = has_alpha && rgba.pixels().any(|p| p[3] < 128);
+            let n = (*n).clamp(1, 256).saturating_sub(transparent as usize).max(1);
+            dominant_colors(&image, n, PaletteAlgorithm::MedianCut).into_iter().map(|c| c.color).collect()
+        }
+        Palette::Fixed(colors) => colors.clone(),
+    };
+    if colors.is_empty() {
+        return image;
+    }
+    let mut nearest = Nearest { colors: &colors, cache: HashMap::new() };
+    let spread = 255.0 / (colors.len() as f32).cbrt();
+    let (width, height) = (rgba.width() as usize, rgba.height() as usize);
+    let mut errors = vec![[0f32; 3]; if dither == Dither::FloydSteinberg { width * height } else { 0 }];
+    for (i, pixel) in rgba.pixels_mut().enumerate() {
+        if has_alpha && pixel[3] < 128 {
+            *pixel = Rgba([0, 0, 0, 0]);
+            continue;
+        }
+        let (x, y) = (i % width, i / width);
+        let offset = match dither {
+            Dither::None => [0.0; 3],
+            Dither::FloydSteinberg => errors[i],
+            Dither::Ordered => [((BAYER[y % 8][x % 8] as f32 + 0.5) / 64.0 - 0.5) * spread; 3],
+        };
+        let target = [0, 1, 2].map(|c| (pixel[c] as f32 + offset[c]).round().clamp(0.0, 255.0) as u8);
+        let color = nearest.find(target);
+        if dither == Dither::FloydSteinberg {
+            let error = [0, 1, 2].map(|c| target[c] as f32 - color[c] as f32);
+            let mut diffuse = |x: usize, y: usize, weight: f32| {
+                if x < width && y < height {
+                    for (e, error) in errors[y * width + x].iter_mut().zip(error) {
+                        *e += error * weight;
+                    }
+                }
+            };
+            diffuse(x + 1, y, 7.0 / 16.0);
+            diffuse(x.wrapping_sub(1), y + 1, 3.0 / 16.0);
+            diffuse(x, y + 1, 5.0 / 16.0);
+            diffuse(x + 1, y + 1, 1.0 / 16.0);
+        }
+        *pixel = Rgba([color[0], color[1], color[2], u8::MAX]);
+    }
+    if has_alpha {
+        DynamicImage::ImageRgba8(rgba)
+    } else {
+        DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(rgba).to_rgb8())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::image::{ImageFormat, RgbImage};
+    use std::collections::HashSet;
+    use crate::image::encode::{encode_image, EncodeOptions};
+    use crate::image::Metadata;
+
+    #[test]
+    fn quantizes_to_an_indexed_png() {
+        let gradient = RgbImage::from_fn(64, 16, |x, _| Rgb([x as u8 * 4, 128, 255 - x as u8 * 4]));
+        for dither in [Dither::None, Dither::FloydSteinberg, Dither::Ordered] {
+            let image = quantize(DynamicImage::ImageRgb8(gradient.clone()), &Palette::Colors(4), dither);
+            assert!(image.to_rgb8().pixels().collect::<HashSet<_>>().len() <= 4);
+
+            let data = encode_image(image.clone(), &Metadata::default(), ImageFormat::Png, &EncodeOptions::default().indexed(true)).unwrap();
+            // Two bits per pixel, palette color type.
+            assert_eq!((data[24], data[25]), (2, 3));
+            assert_eq!(::image::load_from_memory(&data).unwrap().to_rgb8(), image.to_rgb8());
+        }
+    }
+}
@@ -1,8 +1,11 @@
+mod error;
 mod image;
 mod transform;
 mod util;
 
-pub use crate::image::{Image, Format, DataSource};
+pub use crate::error::{Error, Result};
+pub use crate::image::{Image, Format, DataSource, HeifImages, Metadata, MetadataKind, ColorProfile, EncodeOptions, Pages, PlannedOutput, Pattern, DominantColor, PaletteAlgorithm, Palette, Dither, set_pdfium_library_path};
+pub use crate::transform::Resize;
 
 
 #[cfg(test)]
@@ -18,4 +21,32 @@ mod tests {
         assert_eq!(2 + 2, 4);
         Ok(())
     }
+
+    #[test]
+    fn encodes_to_a_buffer() -> Result<()> {
+        let data = std::fs::read("data/1024.png")?;
+        let mut buffer = std::io::Cursor::new(Vec::new());
+        Image::read_from(data.as_slice(), Format::Png)?
+            .set_width(16)
+            .write_to(&mut buffer, Format::Jpeg, EncodeOptions::default().quality(90))?;
+        let decoded = Image::read(buffer.get_ref(), Format::Jpeg)?.to_image()?;
+        assert_eq!(decoded.width(), 16);
+        Ok(())
+    }
+
+    #[test]
+    fn canvas_is_sized_by_options() -> Result<()> {
+        let canvas = Image::new(DataSource::Canvas(Pattern::Solid(::image::Rgba([0, 0, 0, 0]))))
+            .set_width(10)
+            .to_image()?;
+        assert_eq!((canvas.width(), canvas.height()), (10, 10));
+        assert!(canvas.color().has_alpha());
+        Ok(())
+    }
+
+    #[test]
+    fn unknown_extension_is_an_error() {
+        assert!(matches!(Image::open("data/1024"), Err(Error::UnknownFormat(_))));
+        assert!(matches!(Image::open("data/1024.xyz"), Err(Error::UnknownFormat(_))));
+    }
 }
\ No newline at end of file
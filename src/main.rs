@@ -1,30 +1,88 @@
 #![allow(unused)]
 
-use std::path::{Path, PathBuf};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Component, Path, PathBuf};
+use std::process;
 use std::str::FromStr;
+use std::time::{Duration, Instant};
 
 use anyhow::Result;
-use clap::Arg;
-use crate::image::{Format};
+use clap::{Arg, ArgMatches};
+use log::LevelFilter;
+use rayon::prelude::*;
+use crate::batch::{collect_inputs, Filter, Input};
+use crate::image::{ColorProfile, Dither, EncodeOptions, Format, HeifImages, Image, MetadataKind, Palette, PaletteAlgorithm, PlannedOutput, set_pdfium_library_path};
 
-use crate::util::{create_path, resolve_hex_color, resolve_image};
+use crate::manifest::Manifest;
+use crate::options::{Options, Step};
+use crate::watch::{watch, WatchOptions};
+use crate::util::{content_hash, create_path, is_pattern, resolve_image};
 
+mod batch;
 mod cli;
+mod manifest;
+mod options;
+mod recipe;
+mod error;
 mod util;
+mod watch;
 mod image;
 mod transform;
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 const NAME: &str = env!("CARGO_PKG_NAME");
 
+/// Exit code when an input failed, and with --keep-going, when every input failed.
+const EXIT_FAILURE: i32 = 1;
+/// Exit code when the command line is invalid, as for clap's own errors.
+const EXIT_USAGE: i32 = 2;
+/// Exit code with --keep-going when some inputs failed, but others were converted.
+const EXIT_PARTIAL: i32 = 3;
+
+
+/// An invalid command line option, as opposed to a problem with one of the inputs.
+#[derive(Debug, thiserror::Error)]
+#[error("{0}")]
+struct UsageError(String);
+
+
+#[derive(Default)]
+struct Summary {
+    converted: usize,
+    skipped: usize,
+    failed: usize,
+}
+
+
+impl Summary {
+    fn exit_code(&self) -> i32 {
+        match (self.failed, self.converted + self.skipped) {
+            (0, _) => 0,
+            (_, 0) => EXIT_FAILURE,
+            _ => EXIT_PARTIAL,
+        }
+    }
+}
+
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} converted, {} skipped, {} failed", self.converted, self.skipped, self.failed)
+    }
+}
+
 
 /// Based on the command line inputs, infer the format of the input data.
 fn resolve_input_format(input: &str, input_format: Option<&str>) -> Result<Format> {
     if let Some(format) = input_format {
-        return Format::from_str(format).map_err(|_| anyhow::anyhow!("Unknown input format"));
+        return Format::from_str(format).map_err(|_| UsageError(format!("Unknown input format: {}", format)).into());
     }
-    if input.starts_with('#') && vec![4, 5, 7, 9].contains(&input.len()) {
-        return Ok(Format::Bmp);
+    // Colors and patterns are canvases, which are written as PNG unless told otherwise.
+    if is_pattern(input) {
+        return Ok(Format::Png);
     }
     if let Some(ext) = Path::new(input).extension() {
         let ext = ext.to_string_lossy();
@@ -36,12 +94,13 @@ fn resolve_input_format(input: &str, input_format: Option<&str>) -> Result<Forma
 
 fn resolve_output_format(output_path: &Option<&str>, output_format: Option<&str>, input_format: Format) -> Result<Format> {
     if let Some(output) = output_format {
-        return Format::from_str(output).map_err(|_| anyhow::anyhow!("Unknown output format"));
+        return Format::from_str(output).map_err(|_| UsageError(format!("Unknown output format: {}", output)).into());
     }
+    // An output path may leave its extension to `{format}`, which is filled in with this.
     if let Some(output) = output_path {
-        if let Some(ext) = Path::new(output).extension() {
+        if let Some(ext) = Path::new(output).extension().filter(|ext| !ext.to_string_lossy().contains("{format}")) {
             let ext = ext.to_string_lossy().to_lowercase();
-            return Format::from_str(&ext).map_err(|_| anyhow::anyhow!("Unknown output format"));
+            return Format::from_str(&ext).map_err(|_| UsageError(format!("Unknown output format: {}", ext)).into());
         }
     }
     Ok(match input_format {
@@ -50,55 +109,658 @@ fn resolve_output_format(output_path: &Option<&str>, output_format: Option<&str>
         Format::Heif => Format::Jpeg,
         Format::Pdf => Format::Png,
         Format::Bmp => Format::Png,
+        Format::Tiff => Format::Tiff,
+        Format::WebP => Format::Png,
+        Format::Gif => Format::Png,
+    })
+}
+
+
+/// Whether `output` would overwrite `input`, comparing their resolved paths so that e.g. `./a.png`
+/// and `a.png` match.
+fn is_same_file(output: &str, input: &str) -> bool {
+    match (fs::canonicalize(output), fs::canonicalize(input)) {
+        (Ok(output), Ok(input)) => output == input,
+        _ => output == input,
+    }
+}
+
+
+/// Place the output path template in the output directory, if there is one, and next to the input
+/// with --relative-to-input. Inputs found in a directory or glob keep their relative path in the
+/// output directory. Otherwise, the template is relative to the current directory.
+fn resolve_output_template(template: &str, input: &Input, options: &Options) -> String {
+    let mut dir = PathBuf::new();
+    if options.relative_to_input.unwrap_or_default() {
+        dir = Path::new(&input.path).parent().map(PathBuf::from).unwrap_or_default();
+    } else if options.out_dir.is_some() {
+        dir = input.relative_dir.clone();
+    }
+    if let Some(out_dir) = &options.out_dir {
+        dir = dir.join(out_dir);
+    }
+    // `join` would add a trailing separator to an empty template.
+    if dir.as_os_str().is_empty() {
+        return template.to_string();
+    }
+    dir.join(template).to_string_lossy().into_owned()
+}
+
+
+/// The template of the paths an input's images are saved to, placed by [`resolve_output_template`].
+/// Without -o, PDFs and HEIF files with several images are numbered by page.
+fn output_template(input: &Input, options: &Options, input_format: Format, heif_images: HeifImages) -> Result<String> {
+    let output_path = options.output.as_deref();
+    let output_format = resolve_output_format(&output_path, options.output_format.as_deref(), input_format)?;
+    let path_template = output_path.map(|path| path.replace("{format}", output_format.as_str())).unwrap_or_else(
+        || match input_format {
+            Format::Pdf => format!("{{}}_{{i}}.{}", output_format.as_str()),
+            Format::Heif if heif_images != HeifImages::Primary => format!("{{}}_{{i}}.{}", output_format.as_str()),
+            _ => format!("{{}}.{}", output_format.as_str()),
+        }
+    );
+    Ok(resolve_output_template(&path_template, input, options))
+}
+
+
+/// The path template a --dominant swatch is saved to. Swatches are PNGs unless the path or
+/// --output-format says otherwise.
+fn swatch_template(output: &str, input: &Input, options: &Options) -> Result<String> {
+    let format = resolve_output_format(&Some(output), options.output_format.as_deref(), Format::Png)?;
+    Ok(resolve_output_template(&output.replace("{format}", format.as_str()), input, options))
+}
+
+
+/// Where an input's first output would be written, as far as that's known before converting it.
+/// Inputs that are printed, converted in place, or whose output depends on the converted image,
+/// e.g. through `{hash}`, have none. Neither do inputs of unknown format, which fail when they're
+/// converted instead, so that --keep-going still converts the others.
+fn first_output(input: &Input, options: &Options) -> Result<Option<String>> {
+    let filepath = input.path.as_str();
+    if !input.is_supported() || options.metadata.unwrap_or_default() || options.in_place.unwrap_or_default() {
+        return Ok(None);
+    }
+    let template = match &options.output {
+        Some(output) if options.dominant.is_some() => swatch_template(output, input, options)?,
+        None if options.dominant.is_some() || is_pattern(filepath) => return Ok(None),
+        _ => {
+            let input_format = match resolve_input_format(filepath, options.input_format.as_deref()) {
+                Ok(format) => format,
+                Err(e) if e.is::<UsageError>() => return Err(e),
+                Err(_) => return Ok(None),
+            };
+            output_template(input, options, input_format, heif_images(options)?)?
+        }
+    };
+    let output = create_path(&template, &PathBuf::from(filepath), 1, 1);
+    Ok((!output.contains('{')).then_some(output))
+}
+
+
+/// Inputs are converted in parallel, so two of them mustn't write the same output, and neither
+/// must two outputs of one input. Fail on the first clash, or with `keep_going`, warn and leave out
+/// the later input. Returns the inputs kept, and how many were left out.
+fn without_clashing_outputs(inputs: Vec<Input>, specs: &[Options], keep_going: bool) -> Result<(Vec<Input>, usize)> {
+    let mut claimed: HashMap<PathBuf, String> = HashMap::new();
+    let mut kept = Vec::new();
+    let mut left_out = 0;
+    for input in inputs {
+        let mut outputs = Vec::new();
+        for options in specs {
+            // Outputs don't exist yet, so `./a.png` and `a.png` are matched without resolving them.
+            outputs.extend(first_output(&input, options)?
+                .map(|output| Path::new(&output).components().filter(|c| *c != Component::CurDir).collect::<PathBuf>()));
+        }
+        let clash = outputs.iter().enumerate().find_map(|(i, output)| {
+            if outputs[..i].contains(output) {
+                return Some(format!("{} would be written to {} twice", input.path, output.display()));
+            }
+            claimed.get(output).map(|other| format!("{} and {} would both be written to {}", other, input.path, output.display()))
+        });
+        if let Some(message) = clash {
+            if !keep_going {
+                return Err(UsageError(message).into());
+            }
+            log::warn!("Skipping {}: {}", input.path, message);
+            left_out += 1;
+            continue;
+        }
+        claimed.extend(outputs.into_iter().map(|output| (output, input.path.clone())));
+        kept.push(input);
+    }
+    Ok((kept, left_out))
+}
+
+
+/// The files named by options like --palette-file, whose contents are part of the conversion, so
+/// that editing them counts as a change.
+fn option_files(options: &Options) -> Vec<&str> {
+    let steps = options.transforms.iter().flatten().filter_map(|step| match step {
+        Step::Quantize(quantize) => quantize.palette_file.as_deref(),
+        Step::Resize(_) => None,
+    });
+    [options.palette_file.as_deref(), options.convert_profile.as_deref()].into_iter().flatten().chain(steps).collect()
+}
+
+
+/// The conversion options of every output, as recorded in a manifest, with a hash of the files
+/// that options like --palette-file name.
+fn conversion_options(specs: &[Options]) -> Result<String> {
+    specs.iter()
+        .map(|options| {
+            let mut conversion = format!("{:?}", options.conversion());
+            // --convert-profile also takes profile names, which aren't files.
+            for path in option_files(options).into_iter().filter(|path| Path::new(path).is_file()) {
+                conversion += &format!(" {}:{}", path, content_hash(&fs::read(path)?));
+            }
+            Ok(conversion)
+        })
+        .collect::<Result<Vec<_>>>()
+        .map(|options| options.join("\n"))
+}
+
+
+/// Why the input needn't be converted, with --skip-existing or --newer-only: every output planned
+/// for it exists, or is newer than the input. Outputs whose path depends on the converted image,
+/// e.g. through `{hash}`, can't be checked, so the input is converted then.
+fn skip_reason(input: &str, planned: &[PlannedOutput], options: &Options) -> Result<Option<String>> {
+    let modified = planned.iter()
+        .map(|output| match fs::metadata(&output.path) {
+            Ok(metadata) if !output.path.contains('{') => metadata.modified().map(Some),
+            _ => Ok(None),
+        })
+        .collect::<io::Result<Option<Vec<_>>>>()?;
+    let oldest = match modified.and_then(|modified| modified.into_iter().min()) {
+        Some(oldest) => oldest,
+        None => return Ok(None),
+    };
+    let (exist, newer) = match planned {
+        [output] => (format!("{} exists", output.path), format!("{} is newer", output.path)),
+        _ => (format!("all {} outputs exist", planned.len()), format!("all {} outputs are newer", planned.len())),
+    };
+    if options.skip_existing.unwrap_or_default() {
+        return Ok(Some(exist));
+    }
+    if options.newer_only.unwrap_or_default() && oldest >= fs::metadata(input)?.modified()? {
+        return Ok(Some(newer));
+    }
+    Ok(None)
+}
+
+
+/// What --dry-run prints for an input: each output it would write, with its size when known.
+fn describe_plan(input: &str, planned: &[PlannedOutput]) -> Vec<u8> {
+    planned.iter()
+        .map(|output| match output.size {
+            Some((width, height)) => format!("{} -> {} ({}x{})\n", input, output.path, width, height),
+            None => format!("{} -> {}\n", input, output.path),
+        })
+        .collect::<String>()
+        .into_bytes()
+}
+
+
+/// Log to stderr: warnings by default, each step with -v, and details with -vv. `RUST_LOG`
+/// overrides this, e.g. to see the logs of libraries.
+fn init_logging(verbose: u8) {
+    let level = match verbose {
+        0 => LevelFilter::Warn,
+        1 => LevelFilter::Info,
+        _ => LevelFilter::Debug,
+    };
+    env_logger::Builder::new()
+        .filter_level(LevelFilter::Warn)
+        .filter_module(NAME, level)
+        .format_timestamp(None)
+        .parse_default_env()
+        .init();
+}
+
+
+/// What became of an input that didn't fail.
+enum Outcome {
+    /// The input was converted, or printed. Holds whatever should go to stdout, and the files written.
+    Converted(Vec<u8>, Vec<PathBuf>),
+    /// The input was left alone, for this reason.
+    Skipped(String),
+}
+
+
+/// Which images of a HEIF file to convert.
+fn heif_images(options: &Options) -> Result<HeifImages> {
+    HeifImages::from_str(options.heif_images.as_deref().unwrap())
+        .map_err(|_| UsageError("Unknown HEIF image selection".to_string()).into())
+}
+
+
+/// The palette to reduce colors to: `colors` colors, or those in `palette_file`.
+fn palette(colors: Option<usize>, palette_file: Option<&str>) -> Result<Option<Palette>> {
+    Ok(match (colors, palette_file) {
+        (Some(colors), None) => Some(Palette::Colors(colors)),
+        (None, Some(path)) => Some(Palette::read(path)?),
+        (None, None) => None,
+        (Some(_), Some(_)) => return Err(UsageError("--colors can't be used with --palette-file".to_string()).into()),
     })
 }
 
 
-fn main() -> Result<()> {
-    let args = cli::clap_app().get_matches();
+/// Add the resize and color operations of `options` to `im`, then its `transforms` in order.
+fn add_operations(mut im: Image, options: &Options) -> Result<Image> {
+    if let Some(width) = options.width {
+        im = im.set_width(width);
+    }
+    if let Some(height) = options.height {
+        im = im.set_height(height);
+    }
+    if let Some(scale) = options.scale {
+        im = im.scale(scale);
+    }
+    if let Some(max_width) = options.max_width {
+        im = im.max_width(max_width);
+    }
+    if let Some(max_height) = options.max_height {
+        im = im.max_height(max_height);
+    }
+    let dither = |dither: Option<&str>| Dither::from_str(dither.unwrap())
+        .map_err(|_| UsageError("Unknown dither method".to_string()));
+    if let Some(palette) = palette(options.colors, options.palette_file.as_deref())? {
+        im = im.quantize(palette, dither(options.dither.as_deref())?);
+    }
+    for step in options.transforms.iter().flatten() {
+        im = match step {
+            Step::Resize(resize) => im.resize(*resize),
+            Step::Quantize(quantize) => {
+                let palette = palette(quantize.colors, quantize.palette_file.as_deref())?
+                    .ok_or_else(|| UsageError("A quantize transform needs colors or a palette-file".to_string()))?;
+                im.quantize(palette, dither(quantize.dither.as_deref().or(options.dither.as_deref()))?)
+            }
+        };
+    }
+    Ok(im)
+}
+
+
+/// Convert a single input, or print what was asked about it. With `pending`, the output is added
+/// there by its decoding options instead of being saved, to be saved along with the outputs that
+/// share them.
+fn convert(input: &Input, options: &Options, pending: Option<&mut HashMap<String, Vec<(Image, String)>>>) -> Result<Outcome> {
+    let filepath = input.path.as_str();
+    if !input.is_supported() {
+        return Ok(Outcome::Skipped("unsupported format".to_string()));
+    }
+    let input_format = resolve_input_format(filepath, options.input_format.as_deref())?;
+    log::info!("Reading {} as {}", filepath, input_format.as_str());
+
+    let heif_images = heif_images(options)?;
+    let mut im = resolve_image(filepath, input_format)?
+        .heif_images(heif_images)
+        .auto_orient(!options.no_auto_orient.unwrap_or_default())
+        .strip(options.strip.unwrap_or_default())
+        .strip_gps(options.strip_gps.unwrap_or_default());
+    if let Some(kinds) = &options.keep {
+        let kinds = kinds.iter()
+            .map(|kind| MetadataKind::from_str(kind).map_err(|_| UsageError(format!("Unknown metadata kind: {}", kind)).into()))
+            .collect::<Result<Vec<_>>>()?;
+        im = im.keep_metadata(&kinds);
+    }
+    if let Some(profile) = &options.convert_profile {
+        let profile = ColorProfile::from_str(profile)
+            .map_err(|_| UsageError(format!("Unknown color profile: {}", profile)))?;
+        im = im.convert_profile(profile);
+    }
+
+    if options.metadata.unwrap_or_default() {
+        return Ok(Outcome::Converted(im.metadata()?.to_string().into_bytes(), Vec::new()));
+    }
+
+    let dry_run = options.dry_run.unwrap_or_default();
+    if let Some(n) = options.dominant {
+        let algorithm = PaletteAlgorithm::from_str(options.palette_algorithm.as_deref().unwrap())
+            .map_err(|_| UsageError("Unknown palette algorithm".to_string()))?;
+        let dry_run = dry_run && options.output.is_some();
+        // A dry run only needs the size of the swatch, not its colors.
+        let colors = if dry_run { Vec::new() } else { im.dominant_colors(n, algorithm)? };
+        let mut printed = String::new();
+        let mut outputs = Vec::new();
+        if let Some(output) = &options.output {
+            let mut swatch = Image::swatch(&colors);
+            if let Some(width) = options.width {
+                swatch = swatch.set_width(width);
+            }
+            if let Some(height) = options.height {
+                swatch = swatch.set_height(height);
+            }
+            let output = swatch_template(output, input, options)?;
+            let output = create_path(&output, &PathBuf::from(filepath), 1, 1);
+            if dry_run {
+                return Ok(Outcome::Converted(describe_plan(filepath, &swatch.plan(&output)?), Vec::new()));
+            }
+            outputs.push(swatch.save(&output)?);
+        } else if options.palette_format.as_deref() == Some("json") {
+            let colors = colors.iter()
+                .map(|c| format!("{{\"color\": \"{}\", \"share\": {:.4}}}", c.hex(), c.share))
+                .collect::<Vec<_>>();
+            printed = format!("[{}]\n", colors.join(", "));
+        } else {
+            for color in colors {
+                printed += &format!("{} {:.1}%\n", color.hex(), color.share * 100.0);
+            }
+        }
+        return Ok(Outcome::Converted(printed.into_bytes(), outputs));
+    }
+
+    let im = add_operations(im, options)?;
+
+    if options.in_place.unwrap_or_default() {
+        if dry_run {
+            return Ok(Outcome::Converted(describe_plan(filepath, &im.plan(filepath)?), Vec::new()));
+        }
+        im.save_in_place(options.backup.unwrap_or_default())?;
+        return Ok(Outcome::Converted(Vec::new(), vec![PathBuf::from(filepath)]));
+    }
 
-    let allow_overwrite = args.is_present("force");
-    let input = args.values_of("input").unwrap();
-    for filepath in input {
-        let input_format = resolve_input_format(filepath, args.value_of("input-format"))?;
+    let output_path = options.output.as_deref();
+    let output_format = resolve_output_format(
+        &output_path,
+        options.output_format.as_deref(),
+        input_format,
+    )?;
+    // A canvas has no input file to name the output after, so it goes to stdout.
+    if output_path.is_none() && is_pattern(filepath) {
+        if dry_run {
+            return Ok(Outcome::Converted(describe_plan(filepath, &im.plan("stdout")?), Vec::new()));
+        }
+        return Ok(Outcome::Converted(im.encode(output_format, EncodeOptions::default())?, Vec::new()));
+    }
+    let path_template = output_template(input, options, input_format, heif_images)?;
+    let buf = PathBuf::from(filepath);
+    let output_path = create_path(path_template.as_str(), &buf, 1, 1);
+    if !options.force.unwrap_or_default() && is_same_file(&output_path, filepath) {
+        eprintln!("Output path is the same as input path. Use --force to overwrite, or --in-place.");
+        return Err(anyhow::anyhow!("Output path is the same as input path."));
+    }
+    if options.skip_existing.unwrap_or_default() || options.newer_only.unwrap_or_default() {
+        if let Some(reason) = skip_reason(filepath, &im.plan(&path_template)?, options)? {
+            return Ok(Outcome::Skipped(reason));
+        }
+    }
+    if dry_run {
+        return Ok(Outcome::Converted(describe_plan(filepath, &im.plan(&path_template)?), Vec::new()));
+    }
+    if let Some(pending) = pending {
+        pending.entry(format!("{:?}", options.decoding())).or_default().push((im, path_template));
+        return Ok(Outcome::Converted(Vec::new(), Vec::new()));
+    }
+    Ok(Outcome::Converted(Vec::new(), im.save_every_image(&path_template)?))
+}
 
-        let mut im = resolve_image(filepath, input_format)?;
 
-        if let Some(width) = args.value_of("width") {
-            im = im.set_width(width.parse()?);
+/// Convert an input with each set of output options, unless the manifest says it hasn't changed
+/// since the last run. The input counts as converted if any of its outputs was. With several
+/// outputs, a file is decoded once, and each of its pages is resized and encoded for every output
+/// before the next page is decoded.
+fn convert_all(input: &Input, specs: &[Options], manifest: Option<&Manifest>) -> Result<Outcome> {
+    let filepath = input.path.as_str();
+    let input_hash = match manifest {
+        Some(manifest) if input.is_supported() && Path::new(filepath).is_file() => {
+            let hash = content_hash(&fs::read(filepath)?);
+            if manifest.is_up_to_date(filepath, &hash) {
+                return Ok(Outcome::Skipped("unchanged since the last run".to_string()));
+            }
+            Some(hash)
         }
-        if let Some(height) = args.value_of("height") {
-            im = im.set_height(height.parse()?);
+        _ => None,
+    };
+    let (mut printed, mut outputs, mut skipped) = (Vec::new(), Vec::new(), None);
+    let mut converted = false;
+    // A canvas is drawn at each output's size instead.
+    let mut pending = (specs.len() > 1 && !is_pattern(filepath)).then(HashMap::new);
+    for options in specs {
+        match convert(input, options, pending.as_mut())? {
+            Outcome::Converted(data, written) => {
+                printed.extend(data);
+                outputs.extend(written);
+                converted = true;
+            }
+            Outcome::Skipped(reason) => skipped = Some(reason),
+        }
+    }
+    for (_, group) in pending.into_iter().flatten() {
+        outputs.extend(Image::save_outputs(group)?);
+    }
+    if let (false, Some(reason)) = (converted, skipped) {
+        return Ok(Outcome::Skipped(reason));
+    }
+    if let (Some(manifest), Some(hash)) = (manifest, input_hash) {
+        if !outputs.is_empty() {
+            manifest.record(filepath, hash, outputs.clone());
         }
-        if let Some(scale) = args.value_of("scale") {
-            im = im.scale(scale.parse()?);
+    }
+    Ok(Outcome::Converted(printed, outputs))
+}
+
+
+/// The option sets to convert each input with: the options given on the command line for each
+/// output, merged with each output of the --recipe if there is one, then with the defaults. Options
+/// on the command line take precedence over the recipe's, except that a recipe with several outputs
+/// names them itself.
+fn output_specs(args: &ArgMatches, watch: bool) -> Result<Vec<Options>> {
+    let defaults = Options::defaults(args, watch)?;
+    let recipe = match args.value_of("recipe") {
+        Some(path) => recipe::read(path)?,
+        None => vec![Options::default()],
+    };
+    let given = Options::given(args, watch)?;
+    if recipe.len() > 1 {
+        // Every output of the recipe would be written to the same path.
+        if let Some(name) = given.iter().find_map(|given| match given {
+            Options { output: Some(_), .. } => Some("output"),
+            Options { output_format: Some(_), .. } => Some("output-format"),
+            _ => None,
+        }) {
+            return Err(UsageError(format!("--{} can't be used with a recipe that has several outputs", name)).into());
         }
-        if let Some(max_width) = args.value_of("max-width") {
-            im = im.max_width(max_width.parse()?);
+    }
+    let mut specs = Vec::new();
+    for given in given {
+        for output in &recipe {
+            let options = given.clone().or(output.clone()).or(defaults.clone());
+            options.check()?;
+            specs.push(options);
         }
-        if let Some(max_height) = args.value_of("max-height") {
-            im = im.max_height(max_height.parse()?);
+    }
+    Ok(specs)
+}
+
+
+/// Only convert the files in directories and globs that --include and --exclude allow.
+fn input_filter(options: &Options) -> Result<Filter> {
+    Filter::new(
+        options.include.iter().flatten().map(String::as_str),
+        options.exclude.iter().flatten().map(String::as_str),
+    )
+}
+
+
+fn run<'a>(inputs: impl IntoIterator<Item = &'a str>, specs: &[Options]) -> Result<i32> {
+    let options = &specs[0];
+    if let Some(path) = &options.pdfium_lib {
+        set_pdfium_library_path(path);
+    }
+    if let Some(jobs) = options.jobs {
+        rayon::ThreadPoolBuilder::new().num_threads(jobs).build_global()?;
+    }
+    let mut inputs = collect_inputs(inputs, options.recursive.unwrap_or_default())?;
+    let filter = input_filter(options)?;
+    inputs.retain(|input| filter.accepts(input));
+    let keep_going = options.keep_going.unwrap_or_default();
+    let (inputs, clashing) = without_clashing_outputs(inputs, specs, keep_going)?;
+    let manifest = options.manifest.as_ref()
+        .map(|path| Manifest::open(path, &conversion_options(specs)?))
+        .transpose()?;
+    // Inputs are converted in parallel, but anything printed comes out in the order they were given.
+    let results = inputs.par_iter()
+        .map(|input| {
+            let start = Instant::now();
+            let result = convert_all(input, specs, manifest.as_ref());
+            match &result {
+                Ok(Outcome::Converted(..)) => log::info!("Done with {} in {:.2?}", input.path, start.elapsed()),
+                Ok(Outcome::Skipped(reason)) => log::info!("Skipped {}: {}", input.path, reason),
+                Err(e) if keep_going && !e.is::<UsageError>() => eprintln!("Failed to convert {}: {:?}", input.path, e),
+                _ => {}
+            }
+            result
+        });
+    let results = if keep_going {
+        Ok(results.collect::<Vec<_>>())
+    } else {
+        results.collect::<Result<Vec<_>>>().map(|results| results.into_iter().map(Ok).collect())
+    };
+    // Record the inputs that were converted, even if another one failed.
+    if let Some(manifest) = manifest.as_ref().filter(|_| !options.dry_run.unwrap_or_default()) {
+        manifest.write()?;
+    }
+    let results = results?;
+
+    let mut summary = Summary { skipped: clashing, ..Summary::default() };
+    let mut stdout = io::stdout();
+    for result in results {
+        match result {
+            Ok(Outcome::Converted(data, _)) => {
+                stdout.write_all(&data)?;
+                summary.converted += 1;
+            }
+            Ok(Outcome::Skipped(_)) => summary.skipped += 1,
+            Err(e) if e.is::<UsageError>() => return Err(e),
+            Err(_) => summary.failed += 1,
         }
+    }
+    if keep_going {
+        eprintln!("{}", summary);
+    }
+    Ok(summary.exit_code())
+}
+
 
-        let output_path = args.value_of("output");
-        let output_format = resolve_output_format(
-            &output_path,
-            args.value_of("output-format"),
-            input_format,
-        )?;
-        let path_template = output_path.map(String::from).unwrap_or_else(
-            || match input_format {
-                Format::Pdf => format!("{{}}_{{i}}.{}", output_format.as_str()),
-                _ => format!("{{}}.{}", output_format.as_str()),
+/// Convert images in the watched directory as they change, until interrupted.
+fn run_watch(dir: &str, specs: &[Options]) -> Result<i32> {
+    let options = &specs[0];
+    if specs.iter().any(|options| options.in_place.unwrap_or_default()) {
+        return Err(UsageError("--in-place can't be used with watch, as each conversion would trigger another".to_string()).into());
+    }
+    if let Some(path) = &options.pdfium_lib {
+        set_pdfium_library_path(path);
+    }
+    let watch_options = WatchOptions {
+        recursive: options.recursive.unwrap_or_default(),
+        poll: options.poll.unwrap_or_default(),
+        interval: Duration::from_millis(options.interval.unwrap()),
+        debounce: Duration::from_millis(options.debounce.unwrap()),
+    };
+    let manifest = options.manifest.as_ref()
+        .map(|path| Manifest::open(path, &conversion_options(specs)?))
+        .transpose()?;
+    let filter = input_filter(options)?;
+    watch(Path::new(dir), &watch_options, |input| {
+        if !filter.accepts(input) {
+            return Ok(Vec::new());
+        }
+        match convert_all(input, specs, manifest.as_ref())? {
+            Outcome::Converted(data, outputs) => {
+                io::stdout().write_all(&data)?;
+                eprintln!("Converted {}", input.path);
+                if let Some(manifest) = &manifest {
+                    manifest.write()?;
+                }
+                Ok(outputs)
             }
-        );
-        let buf = PathBuf::from(filepath);
-        let output_path = create_path(path_template.as_str(), &buf, 1, 1);
-        if !args.is_present("force") && output_path == filepath {
-            eprintln!("Output path is the same as input path. Use --force to overwrite.");
-            return Err(anyhow::anyhow!("Output path is the same as input path."));
+            Outcome::Skipped(_) => Ok(Vec::new()),
         }
-        im.save_every_image(&path_template)?;
+    })?;
+    Ok(0)
+}
+
+
+fn main() {
+    // Clap exits with EXIT_USAGE itself when the arguments don't parse.
+    let matches = cli::clap_app().get_matches();
+    let (args, watching) = match matches.subcommand_matches("watch") {
+        Some(args) => (args, true),
+        None => (&matches, false),
+    };
+    let result = output_specs(args, watching).and_then(|specs| {
+        init_logging(specs[0].verbose.unwrap_or_default());
+        if watching {
+            run_watch(args.value_of("input").unwrap(), &specs)
+        } else {
+            run(args.values_of("input").unwrap(), &specs)
+        }
+    });
+    let code = result.unwrap_or_else(|e| {
+        eprintln!("Error: {:?}", e);
+        if e.is::<UsageError>() { EXIT_USAGE } else { EXIT_FAILURE }
+    });
+    process::exit(code);
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("imcon-{}-{}", name, process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn parse(command: &[&str]) -> (ArgMatches, Vec<Options>) {
+        let args = cli::clap_app().get_matches_from(command);
+        let specs = output_specs(&args, false).unwrap();
+        (args, specs)
+    }
+
+    #[test]
+    fn keeps_going_past_inputs_of_unknown_format() {
+        let dir = temp_dir("keep-going");
+        let (good, notes) = (dir.join("good.png"), dir.join("notes"));
+        ::image::RgbImage::new(8, 4).save(&good).unwrap();
+        fs::write(&notes, b"not an image").unwrap();
+
+        let (good, notes) = (good.to_str().unwrap(), notes.to_str().unwrap());
+        let (args, specs) = parse(&["imcon", "-k", good, notes, "--relative-to-input", "-o", "{}.jpg"]);
+        assert_eq!(run(args.values_of("input").unwrap(), &specs).unwrap(), EXIT_PARTIAL);
+        assert!(dir.join("good.jpg").is_file());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn leaves_naming_several_outputs_to_the_recipe() {
+        let dir = temp_dir("recipe");
+        let recipe = dir.join("web.toml");
+        fs::write(&recipe, "[[outputs]]\noutput = '{}_thumb.png'\nwidth = 320\n\n[[outputs]]\noutput = '{}.jpg'\n").unwrap();
+        let recipe = recipe.to_str().unwrap();
+
+        let (_, specs) = parse(&["imcon", "a.jpg", "--recipe", recipe, "-w", "100"]);
+        let outputs = specs.iter().map(|options| (options.output.as_deref(), options.width)).collect::<Vec<_>>();
+        assert_eq!(outputs, [(Some("{}_thumb.png"), Some(100)), (Some("{}.jpg"), Some(100))]);
+        for option in ["-o", "--output-format"] {
+            let args = cli::clap_app().get_matches_from(["imcon", "a.jpg", "--recipe", recipe, option, "png"]);
+            assert!(output_specs(&args, false).err().unwrap().is::<UsageError>());
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn refuses_outputs_written_twice() {
+        let inputs = || vec![Input::new(Path::new("a.jpg"), Path::new("")), Input::new(Path::new("b.jpg"), Path::new(""))];
+        let (_, specs) = parse(&["imcon", "a.jpg", "-o", "x.png", "-w", "100", "-o", "./x.png", "-w", "200"]);
+        assert!(without_clashing_outputs(inputs(), &specs, false).err().unwrap().is::<UsageError>());
+        let (kept, left_out) = without_clashing_outputs(inputs(), &specs, true).unwrap();
+        assert_eq!((kept.len(), left_out), (0, 2));
+
+        let (_, specs) = parse(&["imcon", "a.jpg", "-o", "{}.png", "-o", "{}.webp"]);
+        let (kept, left_out) = without_clashing_outputs(inputs(), &specs, false).unwrap();
+        assert_eq!((kept.len(), left_out), (2, 0));
     }
-    Ok(())
 }
@@ -0,0 +1,119 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use anyhow::Result;
+use crate::util::{content_hash, write_atomically};
+
+
+const HEADER: &str = "# imcon manifest: input, input hash, options hash, outputs";
+
+
+/// What an input was converted to on an earlier run.
+#[derive(Debug, PartialEq)]
+struct Entry {
+    input_hash: String,
+    options_hash: String,
+    outputs: Vec<PathBuf>,
+}
+
+
+/// Records the hash of each input and of the options it was converted with, and the files it was
+/// converted to, so that inputs unchanged since an earlier run can be skipped.
+///
+/// It is a text file with one tab-separated line per input, so it can be read and diffed easily.
+pub struct Manifest {
+    path: PathBuf,
+    /// The hash of the options of this run.
+    options_hash: String,
+    entries: Mutex<BTreeMap<String, Entry>>,
+}
+
+
+fn parse(contents: &str) -> BTreeMap<String, Entry> {
+    contents.lines()
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| {
+            let mut fields = line.split('\t');
+            let input = fields.next()?.to_string();
+            let input_hash = fields.next()?.to_string();
+            let options_hash = fields.next()?.to_string();
+            let outputs = fields.map(PathBuf::from).collect();
+            Some((input, Entry { input_hash, options_hash, outputs }))
+        })
+        .collect()
+}
+
+
+fn format(entries: &BTreeMap<String, Entry>) -> String {
+    let mut formatted = format!("{}\n", HEADER);
+    for (input, entry) in entries {
+        formatted += &format!("{}\t{}\t{}", input, entry.input_hash, entry.options_hash);
+        for output in &entry.outputs {
+            formatted += &format!("\t{}", output.display());
+        }
+        formatted.push('\n');
+    }
+    formatted
+}
+
+
+impl Manifest {
+    /// Read the manifest at `path` for a run with `options`, or start one if it doesn't exist.
+    pub fn open<P: Into<PathBuf>>(path: P, options: &str) -> Result<Self> {
+        let path = path.into();
+        let entries = match fs::read_to_string(&path) {
+            Ok(contents) => parse(&contents),
+            Err(e) if e.kind() == ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self { path, options_hash: content_hash(options.as_bytes()), entries: Mutex::new(entries) })
+    }
+
+    /// Whether `input` was converted with the same contents and options before, and its outputs are
+    /// still there.
+    pub fn is_up_to_date(&self, input: &str, input_hash: &str) -> bool {
+        match self.entries.lock().unwrap().get(input) {
+            Some(entry) => entry.input_hash == input_hash
+                && entry.options_hash == self.options_hash
+                && !entry.outputs.is_empty()
+                && entry.outputs.iter().all(|output| output.exists()),
+            None => false,
+        }
+    }
+
+    pub fn record(&self, input: &str, input_hash: String, outputs: Vec<PathBuf>) {
+        let entry = Entry { input_hash, options_hash: self.options_hash.clone(), outputs };
+        self.entries.lock().unwrap().insert(input.to_string(), entry);
+    }
+
+    /// Write the manifest back, keeping the entries of inputs that weren't part of this run.
+    pub fn write(&self) -> Result<()> {
+        if let Some(dir) = self.path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        write_atomically(&self.path, format(&self.entries.lock().unwrap()).as_bytes())?;
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_what_it_writes() {
+        let mut entries = BTreeMap::new();
+        entries.insert("photos/a.heic".to_string(), Entry {
+            input_hash: "cbf29ce484222325".to_string(),
+            options_hash: "af63bd4c8601b7df".to_string(),
+            outputs: vec![PathBuf::from("web/a_1.jpg"), PathBuf::from("web/a_2.jpg")],
+        });
+        let formatted = format(&entries);
+        assert_eq!(formatted.lines().nth(1), Some("photos/a.heic\tcbf29ce484222325\taf63bd4c8601b7df\tweb/a_1.jpg\tweb/a_2.jpg"));
+        assert_eq!(parse(&formatted), entries);
+    }
+}
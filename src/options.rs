@@ -0,0 +1,420 @@
+use std::ops::Range;
+use std::str::FromStr;
+
+use anyhow::Result;
+use clap::ArgMatches;
+use serde::Deserialize;
+use crate::cli::OUTPUT_OPTIONS;
+use crate::transform::Resize;
+use crate::UsageError;
+
+
+/// The options of the conversion pipeline, by their long name on the command line, as given there
+/// or in a --recipe. Options that weren't given are `None`.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Options {
+    // Decoding
+    pub input_format: Option<String>,
+    pub heif_images: Option<String>,
+    pub no_auto_orient: Option<bool>,
+    pub convert_profile: Option<String>,
+    pub pdfium_lib: Option<String>,
+
+    // Printing instead of converting
+    pub metadata: Option<bool>,
+    pub dominant: Option<usize>,
+    pub palette_algorithm: Option<String>,
+    pub palette_format: Option<String>,
+
+    // Operations
+    pub scale: Option<f32>,
+    pub width: Option<usize>,
+    pub height: Option<usize>,
+    pub max_width: Option<usize>,
+    pub max_height: Option<usize>,
+    pub colors: Option<usize>,
+    pub palette_file: Option<String>,
+    pub dither: Option<String>,
+    /// Operations applied in order after the ones above. Only recipes have them, written as
+    /// `{ resize = { ... } }` in TOML and `resize: ...` in YAML alike.
+    #[serde(default, deserialize_with = "serde_yaml::with::singleton_map_recursive::deserialize")]
+    pub transforms: Option<Vec<Step>>,
+
+    // Writing
+    pub output: Option<String>,
+    pub output_format: Option<String>,
+    pub out_dir: Option<String>,
+    pub relative_to_input: Option<bool>,
+    pub strip: Option<bool>,
+    pub keep: Option<Vec<String>>,
+    pub strip_gps: Option<bool>,
+    pub in_place: Option<bool>,
+    pub backup: Option<bool>,
+    pub force: Option<bool>,
+
+    // Batches
+    pub include: Option<Vec<String>>,
+    pub exclude: Option<Vec<String>>,
+    pub recursive: Option<bool>,
+    pub jobs: Option<usize>,
+    pub keep_going: Option<bool>,
+    pub skip_existing: Option<bool>,
+    pub newer_only: Option<bool>,
+    pub manifest: Option<String>,
+    pub dry_run: Option<bool>,
+    pub verbose: Option<u8>,
+
+    // Watching
+    pub poll: Option<bool>,
+    pub interval: Option<u64>,
+    pub debounce: Option<u64>,
+
+    /// A recipe's outputs, each with its own options on top of these.
+    #[serde(default)]
+    pub outputs: Vec<Options>,
+}
+
+
+/// One of a recipe's `transforms`.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub enum Step {
+    Resize(Resize),
+    Quantize(Quantize),
+}
+
+
+/// Reduce the colors, as --colors or --palette-file do, with the output's --dither unless this sets one.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Quantize {
+    pub colors: Option<usize>,
+    pub palette_file: Option<String>,
+    pub dither: Option<String>,
+}
+
+
+/// Options that can't be used together, as clap checks on the command line.
+const CONFLICTS: &[(&str, &[&str])] = &[
+    ("output", &["output-format"]),
+    ("metadata", &["width", "height", "max-width", "max-height", "scale", "output-format", "output"]),
+    ("dominant", &["metadata", "max-width", "max-height", "scale", "output-format"]),
+    ("colors", &["palette-file"]),
+    ("strip", &["keep"]),
+    ("skip-existing", &["newer-only"]),
+    ("in-place", &["output", "output-format", "out-dir", "relative-to-input", "metadata", "dominant", "skip-existing", "newer-only", "manifest"]),
+];
+
+
+/// Which of the options on the command line to read.
+enum Part {
+    /// The default values of the options that weren't given.
+    Defaults,
+    /// The options given for every output: all of them, except for the options in [`OUTPUT_OPTIONS`]
+    /// given after the first of several `-o`, at this argument index.
+    Common(Option<usize>),
+    /// The options in [`OUTPUT_OPTIONS`] given from an `-o` up to the next one, by argument index.
+    Output(Range<usize>),
+}
+
+
+/// Parse an option's value, if it has one.
+fn parse<T: FromStr>(name: &str, value: Option<&str>) -> Result<Option<T>> {
+    value
+        .map(|value| value.parse().map_err(|_| UsageError(format!("Invalid value for --{}: {}", name, value)).into()))
+        .transpose()
+}
+
+
+impl Options {
+    /// The options given on the command line, for each output. With several `-o`, the options in
+    /// [`OUTPUT_OPTIONS`] after each of them, up to the next, only apply to that output, and take
+    /// precedence over the same options given before the first `-o`. Everything else applies to
+    /// every output.
+    pub fn given(args: &ArgMatches, watch: bool) -> Result<Vec<Options>> {
+        let outputs = args.indices_of("output").map(Iterator::collect::<Vec<_>>).unwrap_or_default();
+        if outputs.len() <= 1 {
+            return Ok(vec![Self::from_args(args, watch, Part::Common(None))?]);
+        }
+        let common = Self::from_args(args, watch, Part::Common(Some(outputs[0])))?;
+        outputs.iter().enumerate()
+            .map(|(i, &start)| {
+                let end = outputs.get(i + 1).copied().unwrap_or(usize::MAX);
+                Ok(Self::from_args(args, watch, Part::Output(start..end))?.or(common.clone()))
+            })
+            .collect()
+    }
+
+    /// The default values of the options that weren't given on the command line, which a recipe
+    /// can still set.
+    pub fn defaults(args: &ArgMatches, watch: bool) -> Result<Options> {
+        Self::from_args(args, watch, Part::Defaults)
+    }
+
+    fn from_args(args: &ArgMatches, watch: bool, part: Part) -> Result<Options> {
+        let applies = |name: &str, index: usize| match &part {
+            Part::Defaults => false,
+            Part::Common(first_output) => !OUTPUT_OPTIONS.contains(&name) || first_output.map_or(true, |first| index < first),
+            Part::Output(range) => OUTPUT_OPTIONS.contains(&name) && range.contains(&index),
+        };
+        // The values given for the part, or with `Part::Defaults`, the default values.
+        let values = |name: &str| -> Vec<&str> {
+            match (args.indices_of(name), args.values_of(name)) {
+                (_, Some(values)) if args.occurrences_of(name) == 0 => values.filter(|_| matches!(part, Part::Defaults)).collect(),
+                (Some(indices), Some(values)) => indices.zip(values).filter(|&(i, _)| applies(name, i)).map(|(_, value)| value).collect(),
+                _ => Vec::new(),
+            }
+        };
+        // Of an option given several times for the same part, the last one counts.
+        let value = |name: &str| values(name).last().copied();
+        let string = |name: &str| value(name).map(str::to_string);
+        let list = |name: &str| Some(values(name)).filter(|values| !values.is_empty())
+            .map(|values| values.into_iter().map(str::to_string).collect());
+        // A flag given without a value, like `-m`, has no index.
+        let flag = |name: &str| match &part {
+            Part::Common(_) if !OUTPUT_OPTIONS.contains(&name) => args.occurrences_of(name) > 0,
+            _ => args.indices_of(name).is_some_and(|mut indices| indices.any(|i| applies(name, i))),
+        }.then_some(true);
+        let count = match part {
+            Part::Common(_) => args.occurrences_of("verbose"),
+            _ => 0,
+        };
+        let mut options = Options {
+            input_format: string("input-format"),
+            heif_images: string("heif-images"),
+            no_auto_orient: flag("no-auto-orient"),
+            convert_profile: string("convert-profile"),
+            pdfium_lib: string("pdfium-lib"),
+            metadata: flag("metadata"),
+            dominant: parse("dominant", value("dominant"))?,
+            palette_algorithm: string("palette-algorithm"),
+            palette_format: string("palette-format"),
+            scale: parse("scale", value("scale"))?,
+            width: parse("width", value("width"))?,
+            height: parse("height", value("height"))?,
+            max_width: parse("max-width", value("max-width"))?,
+            max_height: parse("max-height", value("max-height"))?,
+            colors: parse("colors", value("colors"))?,
+            palette_file: string("palette-file"),
+            dither: string("dither"),
+            transforms: None,
+            output: string("output"),
+            output_format: string("output-format"),
+            out_dir: string("out-dir"),
+            relative_to_input: flag("relative-to-input"),
+            strip: flag("strip"),
+            keep: list("keep"),
+            strip_gps: flag("strip-gps"),
+            in_place: flag("in-place"),
+            backup: flag("backup"),
+            force: flag("force"),
+            include: list("include"),
+            exclude: list("exclude"),
+            recursive: flag("recursive"),
+            jobs: parse("jobs", value("jobs"))?,
+            keep_going: flag("keep-going"),
+            skip_existing: flag("skip-existing"),
+            newer_only: flag("newer-only"),
+            manifest: string("manifest"),
+            dry_run: flag("dry-run"),
+            verbose: (count > 0).then_some(count as u8),
+            ..Options::default()
+        };
+        // Only `watch` has these.
+        if watch {
+            options.poll = flag("poll");
+            options.interval = parse("interval", value("interval"))?;
+            options.debounce = parse("debounce", value("debounce"))?;
+        }
+        Ok(options)
+    }
+
+    /// The options set here, or else in `fallback`. Setting one of a pair of options that can't be
+    /// used together, like --colors and --palette-file, replaces the other in `fallback`.
+    pub fn or(self, mut fallback: Options) -> Options {
+        let set = |flag: Option<bool>| flag == Some(true);
+        if self.output.is_some() {
+            fallback.output_format = None;
+        }
+        if self.output_format.is_some() {
+            fallback.output = None;
+        }
+        if self.colors.is_some() {
+            fallback.palette_file = None;
+        }
+        if self.palette_file.is_some() {
+            fallback.colors = None;
+        }
+        if set(self.strip) {
+            fallback.keep = None;
+        }
+        if self.keep.is_some() {
+            fallback.strip = None;
+        }
+        if set(self.skip_existing) {
+            fallback.newer_only = None;
+        }
+        if set(self.newer_only) {
+            fallback.skip_existing = None;
+        }
+        Options {
+            input_format: self.input_format.or(fallback.input_format),
+            heif_images: self.heif_images.or(fallback.heif_images),
+            no_auto_orient: self.no_auto_orient.or(fallback.no_auto_orient),
+            convert_profile: self.convert_profile.or(fallback.convert_profile),
+            pdfium_lib: self.pdfium_lib.or(fallback.pdfium_lib),
+            metadata: self.metadata.or(fallback.metadata),
+            dominant: self.dominant.or(fallback.dominant),
+            palette_algorithm: self.palette_algorithm.or(fallback.palette_algorithm),
+            palette_format: self.palette_format.or(fallback.palette_format),
+            scale: self.scale.or(fallback.scale),
+            width: self.width.or(fallback.width),
+            height: self.height.or(fallback.height),
+            max_width: self.max_width.or(fallback.max_width),
+            max_height: self.max_height.or(fallback.max_height),
+            colors: self.colors.or(fallback.colors),
+            palette_file: self.palette_file.or(fallback.palette_file),
+            dither: self.dither.or(fallback.dither),
+            transforms: self.transforms.or(fallback.transforms),
+            output: self.output.or(fallback.output),
+            output_format: self.output_format.or(fallback.output_format),
+            out_dir: self.out_dir.or(fallback.out_dir),
+            relative_to_input: self.relative_to_input.or(fallback.relative_to_input),
+            strip: self.strip.or(fallback.strip),
+            keep: self.keep.or(fallback.keep),
+            strip_gps: self.strip_gps.or(fallback.strip_gps),
+            in_place: self.in_place.or(fallback.in_place),
+            backup: self.backup.or(fallback.backup),
+            force: self.force.or(fallback.force),
+            include: self.include.or(fallback.include),
+            exclude: self.exclude.or(fallback.exclude),
+            recursive: self.recursive.or(fallback.recursive),
+            jobs: self.jobs.or(fallback.jobs),
+            keep_going: self.keep_going.or(fallback.keep_going),
+            skip_existing: self.skip_existing.or(fallback.skip_existing),
+            newer_only: self.newer_only.or(fallback.newer_only),
+            manifest: self.manifest.or(fallback.manifest),
+            dry_run: self.dry_run.or(fallback.dry_run),
+            verbose: self.verbose.or(fallback.verbose),
+            poll: self.poll.or(fallback.poll),
+            interval: self.interval.or(fallback.interval),
+            debounce: self.debounce.or(fallback.debounce),
+            outputs: self.outputs,
+        }
+    }
+
+    /// Fail if options that can't be used together are set, e.g. by a recipe.
+    pub fn check(&self) -> Result<()> {
+        let set = |flag: Option<bool>| flag == Some(true);
+        let given = [
+            ("output", self.output.is_some()),
+            ("output-format", self.output_format.is_some()),
+            ("metadata", set(self.metadata)),
+            ("dominant", self.dominant.is_some()),
+            ("width", self.width.is_some()),
+            ("height", self.height.is_some()),
+            ("max-width", self.max_width.is_some()),
+            ("max-height", self.max_height.is_some()),
+            ("scale", self.scale.is_some()),
+            ("colors", self.colors.is_some()),
+            ("palette-file", self.palette_file.is_some()),
+            ("strip", set(self.strip)),
+            ("keep", self.keep.is_some()),
+            ("skip-existing", set(self.skip_existing)),
+            ("newer-only", set(self.newer_only)),
+            ("in-place", set(self.in_place)),
+            ("out-dir", self.out_dir.is_some()),
+            ("relative-to-input", set(self.relative_to_input)),
+            ("manifest", self.manifest.is_some()),
+        ];
+        let is_set = |name: &str| given.iter().any(|&(n, set)| n == name && set);
+        for (name, others) in CONFLICTS {
+            if let Some(other) = others.iter().find(|other| is_set(name) && is_set(other)) {
+                return Err(UsageError(format!("--{} can't be used with --{}", name, other)).into());
+            }
+        }
+        if set(self.backup) && !set(self.in_place) {
+            return Err(UsageError("--backup requires --in-place".to_string()).into());
+        }
+        Ok(())
+    }
+
+    /// The options that change what inputs are converted to, so a manifest can tell whether an
+    /// earlier conversion still holds. Options added to the pipeline belong here too.
+    pub fn conversion(&self) -> Options {
+        Options {
+            output: self.output.clone(),
+            output_format: self.output_format.clone(),
+            out_dir: self.out_dir.clone(),
+            relative_to_input: self.relative_to_input,
+            scale: self.scale,
+            width: self.width,
+            height: self.height,
+            max_width: self.max_width,
+            max_height: self.max_height,
+            colors: self.colors,
+            palette_file: self.palette_file.clone(),
+            dither: self.dither.clone(),
+            transforms: self.transforms.clone(),
+            strip: self.strip,
+            keep: self.keep.clone(),
+            strip_gps: self.strip_gps,
+            ..self.decoding()
+        }
+    }
+
+    /// Options that change how an input is decoded. Outputs that agree on them share one decode.
+    pub fn decoding(&self) -> Options {
+        Options {
+            input_format: self.input_format.clone(),
+            heif_images: self.heif_images.clone(),
+            no_auto_orient: self.no_auto_orient,
+            convert_profile: self.convert_profile.clone(),
+            ..Options::default()
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prefers_its_own_options() {
+        let given = Options { width: Some(100), palette_file: Some("eink.txt".to_string()), ..Options::default() };
+        let recipe = Options { width: Some(200), height: Some(50), colors: Some(16), ..Options::default() };
+        let options = given.or(recipe);
+        assert_eq!((options.width, options.height), (Some(100), Some(50)));
+        assert_eq!(options.colors, None);
+        assert!(options.check().is_ok());
+
+        let options = Options { strip: Some(true), keep: Some(vec!["icc".to_string()]), ..Options::default() };
+        assert!(options.check().is_err());
+        let options = Options { strip: Some(false), keep: Some(vec!["icc".to_string()]), ..Options::default() };
+        assert!(options.check().is_ok());
+    }
+
+    fn given(command: &str) -> Vec<Options> {
+        let args = crate::cli::clap_app().get_matches_from(command.split(' '));
+        Options::given(&args, false).unwrap()
+    }
+
+    #[test]
+    fn assigns_options_to_outputs() {
+        let options = given("imcon a.jpg -w 100 -o b.jpg -h 50");
+        assert_eq!((options.len(), options[0].width, options[0].height), (1, Some(100), Some(50)));
+
+        let options = given("imcon -k a.jpg --strip -w100 -o {}@1x.jpg -o {}@2x.webp --width=200 --keep exif icc -j 2 -w 300");
+        assert_eq!(options.len(), 2);
+        assert_eq!((options[0].output.as_deref(), options[0].width, options[0].strip, options[0].keep.clone()), (Some("{}@1x.jpg"), Some(100), Some(true), None));
+        assert_eq!((options[1].output.as_deref(), options[1].width, options[1].strip), (Some("{}@2x.webp"), Some(300), None));
+        assert_eq!(options[1].keep, Some(vec!["exif".to_string(), "icc".to_string()]));
+        assert!(options.iter().all(|options| options.jobs == Some(2) && options.keep_going == Some(true)));
+
+        let options = given("imcon -o {}.png a.jpg --strip-gps -o {}.jpg --dither=ordered -- -b.jpg");
+        assert_eq!((options[0].strip_gps, options[1].strip_gps), (Some(true), None));
+        assert_eq!((options[1].output.as_deref(), options[1].dither.as_deref()), (Some("{}.jpg"), Some("ordered")));
+    }
+}
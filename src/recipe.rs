@@ -0,0 +1,139 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::{bail, Result};
+use crate::options::Options;
+use crate::UsageError;
+
+
+/// Read a recipe: a reusable set of options, from a TOML file, or a YAML file if it ends in `.yaml`
+/// or `.yml`. Keys are the long names of command line options, e.g. `max-width = 1600` or
+/// `keep = ["exif", "icc"]`. A `transforms` list resizes and reduces colors in the order given,
+/// after the other options. Each entry of an `outputs` list describes one output of every input,
+/// with its own options on top of the common ones, except for those of the whole run, like `jobs`:
+///
+/// ```toml
+/// include = ["*.jpg", "*.png"]
+/// strip-gps = true
+///
+/// [[outputs]]
+/// output = "{}_thumb.png"
+/// transforms = [{ resize = { max-width = 320 } }, { quantize = { colors = 64 } }]
+///
+/// [[outputs]]
+/// max-width = 1600
+/// output = "{}.jpg"
+/// ```
+///
+/// Returns the options of each output, or just the common ones if there is no `outputs` list.
+pub fn read<P: AsRef<Path>>(path: P) -> Result<Vec<Options>> {
+    let path = path.as_ref();
+    let contents = fs::read_to_string(path)?;
+    let is_yaml = path.extension().is_some_and(|ext| ext == "yaml" || ext == "yml");
+    parse(&contents, is_yaml).map_err(|e| UsageError(format!("Invalid recipe {}: {}", path.display(), e)).into())
+}
+
+
+/// The first option set in `output` that applies to the whole run rather than to one output.
+fn batch_option(output: &Options) -> Option<&'static str> {
+    let given = [
+        ("pdfium-lib", output.pdfium_lib.is_some()),
+        ("include", output.include.is_some()),
+        ("exclude", output.exclude.is_some()),
+        ("recursive", output.recursive.is_some()),
+        ("jobs", output.jobs.is_some()),
+        ("keep-going", output.keep_going.is_some()),
+        ("manifest", output.manifest.is_some()),
+        ("dry-run", output.dry_run.is_some()),
+        ("verbose", output.verbose.is_some()),
+        ("poll", output.poll.is_some()),
+        ("interval", output.interval.is_some()),
+        ("debounce", output.debounce.is_some()),
+    ];
+    given.iter().find(|&&(_, set)| set).map(|&(name, _)| name)
+}
+
+
+fn parse(contents: &str, is_yaml: bool) -> Result<Vec<Options>> {
+    let mut recipe: Options = if is_yaml { serde_yaml::from_str(contents)? } else { toml::from_str(contents)? };
+    let outputs = std::mem::take(&mut recipe.outputs);
+    if outputs.iter().any(|output| !output.outputs.is_empty()) {
+        bail!("outputs can't have outputs of their own");
+    }
+    if let Some(name) = outputs.iter().find_map(batch_option) {
+        bail!("{} applies to every output, so it can't be set in outputs", name);
+    }
+    if outputs.is_empty() {
+        return Ok(vec![recipe]);
+    }
+    Ok(outputs.into_iter().map(|output| output.or(recipe.clone())).collect())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::options::{Quantize, Step};
+    use crate::transform::Resize;
+
+    #[test]
+    fn parses_toml_and_yaml_recipes() {
+        let toml = r#"
+            # Thumbnails for the web
+            include = ["*.jpg",
+                       "*.png"]  # and nothing else
+            strip-gps = true
+            output = "[draft] {}#1.jpg"
+            verbose = 2
+
+            [[outputs]]
+            max-width = 320
+            keep = ["exif", "icc"]
+            output = '{}_thumb.png'
+            transforms = [{ quantize = { colors = 16 } }, { resize = { scale = 0.5 } }]
+
+            [[outputs]]
+            scale = 0.5
+        "#;
+        let yaml = "
+# Thumbnails for the web
+include:
+  - '*.jpg'
+  - '*.png'
+strip-gps: true
+output: '[draft] {}#1.jpg'
+verbose: 2
+outputs:
+  - max-width: 320
+    keep:
+      - exif
+      - icc
+    output: thumbs/{}_thumb.png # with a comment
+    transforms:
+      - quantize: {colors: 16}
+      - resize:
+          scale: 0.5
+  - scale: 0.5
+";
+        let toml = parse(toml, false).unwrap();
+        let yaml = parse(yaml, true).unwrap();
+        assert_eq!(toml.len(), 2);
+        assert_eq!(toml[0].include, Some(vec!["*.jpg".to_string(), "*.png".to_string()]));
+        assert_eq!((toml[0].strip_gps, toml[0].verbose, toml[0].max_width), (Some(true), Some(2), Some(320)));
+        assert_eq!(toml[0].output.as_deref(), Some("{}_thumb.png"));
+        assert_eq!(toml[0].transforms, Some(vec![
+            Step::Quantize(Quantize { colors: Some(16), palette_file: None, dither: None }),
+            Step::Resize(Resize { scale: Some(0.5), ..Resize::default() }),
+        ]));
+        assert_eq!((toml[1].output.as_deref(), toml[1].scale), (Some("[draft] {}#1.jpg"), Some(0.5)));
+        assert_eq!(yaml[0].output.as_deref(), Some("thumbs/{}_thumb.png"));
+        assert_eq!(yaml[1], toml[1]);
+        assert_eq!(Options { output: toml[0].output.clone(), ..yaml[0].clone() }, toml[0]);
+
+        assert!(parse("max_width = 320", false).is_err());
+        assert!(parse("[[outputs]]\n[[outputs.outputs]]\nwidth = 1", false).is_err());
+        assert!(parse("jobs = 4\n[[outputs]]\nwidth = 1", false).is_ok());
+        assert!(parse("[[outputs]]\nwidth = 1\njobs = 4", false).is_err());
+        assert!(parse("outputs:\n  - include: ['*.png']", true).is_err());
+    }
+}
@@ -1,4 +1,10 @@
-#[derive(Default, Copy, Clone)]
+use serde::Deserialize;
+use crate::image::{Dither, Palette};
+
+
+/// Target dimensions, by the names of the command line options, e.g. `max-width` in a recipe.
+#[derive(Default, Copy, Clone, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
 pub struct Resize {
     pub width: Option<usize>,
     pub height: Option<usize>,
@@ -33,14 +39,14 @@ impl Resize {
             }
         }
 
-        if self.width.is_some() && self.height.is_some() {
-            width = self.width.unwrap() as f32;
-            height = self.height.unwrap() as f32;
+        if let (Some(target_width), Some(target_height)) = (self.width, self.height) {
+            width = target_width as f32;
+            height = target_height as f32;
         } else if let Some(target_width) = self.width {
-            height *= width / target_width as f32;
+            height *= target_width as f32 / width;
             width = target_width as f32;
         } else if let Some(target_height) = self.height {
-            width *= height / current_height as f32;
+            width *= target_height as f32 / height;
             height = target_height as f32;
         }
         (width as u32, height as u32)
@@ -49,4 +55,24 @@ impl Resize {
 
 #[derive(Clone)]
 pub enum Transform {
+    /// Resize after the transforms before it, unlike the resize every image starts with.
+    Resize(Resize),
+    /// Reduce the image to a palette of at most 256 colors.
+    Quantize { palette: Palette, dither: Dither },
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_aspect_ratio_when_one_side_is_given() {
+        let width = Resize { width: Some(100), ..Resize::default() };
+        assert_eq!(width.calculate_dimensions(300, 200), (100, 66));
+        let height = Resize { height: Some(100), scale: Some(2.0), ..Resize::default() };
+        assert_eq!(height.calculate_dimensions(300, 200), (150, 100));
+        let max = Resize { max_width: Some(150), max_height: Some(120), ..Resize::default() };
+        assert_eq!(max.calculate_dimensions(300, 200), (150, 100));
+    }
 }
@@ -1,52 +1,307 @@
-use std::path::PathBuf;
-use crate::image::{DataSource, Format, Image};
-use anyhow::Result;
-use image::{DynamicImage, Pixel, Rgb, Rgba, RgbaImage, RgbImage};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::image::{DataSource, Format, Image, Pattern};
+use crate::error::{Error, Result};
 
 
+/// Fill in the placeholders of an output path template that depend on the input path and page.
+/// See [`fill_image_placeholders`] for the ones that depend on the output image.
 pub fn create_path(path_template: &str, input_path: &PathBuf, page: usize, n_pages: usize) -> String {
     let places = n_pages.to_string().len();
+    let dir = input_path.parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or_else(|| Path::new("."));
     path_template
-        .replace("{}", input_path.file_stem().unwrap().to_string_lossy().as_ref())
+        .replace("{}", input_path.file_stem().unwrap_or_default().to_string_lossy().as_ref())
         .replace("{i}", format!("{:0places$}", page, places = places).as_ref())
+        .replace("{dir}", dir.to_string_lossy().as_ref())
+        .replace("{filename}", input_path.file_name().unwrap_or_default().to_string_lossy().as_ref())
+        .replace("{ext}", input_path.extension().unwrap_or_default().to_string_lossy().as_ref())
+        .replace("{page_count}", &n_pages.to_string())
+}
+
+
+/// A calendar date and time, without a time zone.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct DateTime {
+    pub year: i64,
+    pub month: u32,
+    pub day: u32,
+    pub hour: u32,
+    pub minute: u32,
+    pub second: u32,
+}
+
+
+impl DateTime {
+    /// The date and time of `time` in UTC.
+    pub fn from_system_time(time: SystemTime) -> Self {
+        let seconds = match time.duration_since(UNIX_EPOCH) {
+            Ok(since) => since.as_secs() as i64,
+            Err(e) => -(e.duration().as_secs() as i64),
+        };
+        let (days, time) = (seconds.div_euclid(86400), seconds.rem_euclid(86400) as u32);
+        // Days to a civil date, after Howard Hinnant's `civil_from_days`.
+        let days = days + 719468;
+        let era = days.div_euclid(146097);
+        let day_of_era = days.rem_euclid(146097);
+        let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let month = (5 * day_of_year + 2) / 153;
+        let day = (day_of_year - (153 * month + 2) / 5 + 1) as u32;
+        let month = (if month < 10 { month + 3 } else { month - 9 }) as u32;
+        let year = year_of_era + era * 400 + (month <= 2) as i64;
+        Self { year, month, day, hour: time / 3600, minute: time / 60 % 60, second: time % 60 }
+    }
+
+    /// Format the date like strftime. Supports `%Y`, `%y`, `%m`, `%d`, `%H`, `%M`, `%S`, `%F`
+    /// (`%Y-%m-%d`), `%T` (`%H:%M:%S`) and `%%`. Anything else is kept as is.
+    pub fn format(&self, format: &str) -> String {
+        let mut formatted = String::new();
+        let mut chars = format.chars();
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                formatted.push(c);
+                continue;
+            }
+            match chars.next() {
+                Some('Y') => formatted += &format!("{:04}", self.year),
+                Some('y') => formatted += &format!("{:02}", self.year.rem_euclid(100)),
+                Some('m') => formatted += &format!("{:02}", self.month),
+                Some('d') => formatted += &format!("{:02}", self.day),
+                Some('H') => formatted += &format!("{:02}", self.hour),
+                Some('M') => formatted += &format!("{:02}", self.minute),
+                Some('S') => formatted += &format!("{:02}", self.second),
+                Some('F') => formatted += &self.format("%Y-%m-%d"),
+                Some('T') => formatted += &self.format("%H:%M:%S"),
+                Some('%') => formatted.push('%'),
+                Some(other) => {
+                    formatted.push('%');
+                    formatted.push(other);
+                }
+                None => formatted.push('%'),
+            }
+        }
+        formatted
+    }
+}
+
+
+/// A 64-bit FNV-1a hash of `data` in hex. Stable across platforms and versions, so it can name files.
+pub fn content_hash(data: &[u8]) -> String {
+    let hash = data.iter().fold(0xcbf2_9ce4_8422_2325u64, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+    });
+    format!("{:016x}", hash)
+}
+
+
+/// Replace `{date}` with `date` as `%Y-%m-%d`, and `{date:<format>}` with it in a strftime format.
+fn replace_dates(template: &str, date: DateTime) -> String {
+    let mut replaced = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find("{date") {
+        let after = &rest[start + "{date".len()..];
+        let (format, end) = if after.starts_with('}') {
+            ("%Y-%m-%d", 1)
+        } else if let Some(end) = after.strip_prefix(':').and_then(|spec| spec.find('}')) {
+            (&after[1..end + 1], end + 2)
+        } else {
+            replaced += &rest[..start + "{date".len()];
+            rest = after;
+            continue;
+        };
+        replaced += &rest[..start];
+        replaced += &date.format(format);
+        rest = &after[end..];
+    }
+    replaced + rest
+}
+
+
+/// Fill in `{width}`, `{height}` and `{format}` (the path's extension) in an output path.
+pub fn fill_size_placeholders(path: &str, width: u32, height: u32) -> String {
+    let format = Path::new(path).extension().unwrap_or_default().to_string_lossy().to_lowercase();
+    path.replace("{width}", &width.to_string())
+        .replace("{height}", &height.to_string())
+        .replace("{format}", &format)
+}
+
+
+/// Fill in the placeholders of an output path that depend on the image being written: those of
+/// [`fill_size_placeholders`], `{hash}` of the encoded `data`, and `{date}`.
+pub fn fill_image_placeholders(path: &str, width: u32, height: u32, data: &[u8], date: DateTime) -> String {
+    let mut path = fill_size_placeholders(path, width, height);
+    if path.contains("{hash}") {
+        path = path.replace("{hash}", &content_hash(data));
+    }
+    replace_dates(&path, date)
+}
+
+
+/// Distinguishes the temporary files of threads writing into the same directory.
+static TEMP_FILES: AtomicUsize = AtomicUsize::new(0);
+
+
+/// Write `data` to a hidden temporary file next to `path`, give it the permissions and
+/// modification time of `original` if set, and flush it to disk, so it can be renamed over `path`.
+fn write_temp_file(path: &Path, data: &[u8], original: Option<&fs::Metadata>) -> Result<PathBuf> {
+    let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or_else(|| Path::new("."));
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let n = TEMP_FILES.fetch_add(1, Ordering::Relaxed);
+    let temp = dir.join(format!(".{}.imcon-{}-{}.tmp", name, std::process::id(), n));
+    let mut file = OpenOptions::new().write(true).create_new(true).open(&temp)?;
+    let written = file.write_all(data).and_then(|_| {
+        if let Some(original) = original {
+            file.set_permissions(original.permissions())?;
+            file.set_modified(original.modified()?)?;
+        }
+        file.sync_all()
+    });
+    if let Err(e) = written {
+        let _ = fs::remove_file(&temp);
+        return Err(e.into());
+    }
+    Ok(temp)
+}
+
+
+/// Rename `temp` over `path`, removing it if that fails, and flush the directory entry on Unix.
+fn rename_over(temp: &Path, path: &Path) -> Result<()> {
+    if let Err(e) = fs::rename(temp, path) {
+        let _ = fs::remove_file(temp);
+        return Err(e.into());
+    }
+    #[cfg(unix)]
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+
+/// Write `data` to `path` atomically: readers see either the old file or the complete new one,
+/// never a partial write, even if imcon is interrupted.
+pub fn write_atomically(path: &Path, data: &[u8]) -> Result<()> {
+    let temp = write_temp_file(path, data, None)?;
+    rename_over(&temp, path)
+}
+
+
+/// Atomically replace the existing file at `path` with `data`, keeping its permissions and
+/// modification time. With `backup`, the original is first copied to `<path>.bak`.
+pub fn replace_file(path: &Path, data: &[u8], backup: bool) -> Result<()> {
+    let original = fs::metadata(path)?;
+    if backup {
+        let mut backup_path = path.as_os_str().to_owned();
+        backup_path.push(".bak");
+        // Setting the modification time needs a handle open for writing, at least on Windows.
+        let mut file = OpenOptions::new().write(true).create(true).truncate(true).open(&backup_path)?;
+        io::copy(&mut File::open(path)?, &mut file)?;
+        file.set_permissions(original.permissions())?;
+        file.set_modified(original.modified()?)?;
+    }
+    let temp = write_temp_file(path, data, Some(&original))?;
+    rename_over(&temp, path)
 }
 
 /// Takes a string representing a hex_color.
-pub fn resolve_hex_color(mut hex_color: &str) -> anyhow::Result<Vec<u8>> {
+pub fn resolve_hex_color(mut hex_color: &str) -> Result<Vec<u8>> {
     if hex_color.starts_with("#") {
         hex_color = &hex_color[1..];
     }
+    if !hex_color.is_ascii() {
+        return Err(Error::InvalidColor(hex_color.to_string()));
+    }
     let mut bytes = Vec::new();
     let size = hex_color.len();
     if size == 3 || size == 4 {
         for i in 0..size {
-            let byte = u8::from_str_radix(&hex_color[i..i+1], 16)? * 0x11;
+            let byte = u8::from_str_radix(&hex_color[i..i+1], 16)
+                .map_err(|_| Error::InvalidColor(hex_color.to_string()))? * 0x11;
             bytes.push(byte);
         }
     } else if size == 6 || size == 8 {
         for i in 0..(size/2) {
-            let byte = u8::from_str_radix(&hex_color[(i*2)..(i*2+2)], 16)?;
+            let byte = u8::from_str_radix(&hex_color[(i*2)..(i*2+2)], 16)
+                .map_err(|_| Error::InvalidColor(hex_color.to_string()))?;
             bytes.push(byte);
         }
     } else {
-        return Err(anyhow::anyhow!("Invalid hex color code."));
+        return Err(Error::InvalidColor(hex_color.to_string()));
     }
     Ok(bytes)
 }
 
+/// Whether an input names a generated pattern, like `#ff0000`, `red` or `linear-gradient(red, blue)`,
+/// rather than a file. Files take precedence, so an image named `red` can still be read. Calls to a
+/// pattern function with invalid arguments count too, so that they fail as invalid patterns.
+pub fn is_pattern(input: &str) -> bool {
+    !Path::new(input).exists() && (Pattern::from_str(input).is_ok() || Pattern::is_function_call(input))
+}
+
 pub fn resolve_image(input: &str, input_format: Format) -> Result<Image> {
-    if input.starts_with('#') {
-        let input = resolve_hex_color(input)?;
-        let image = if input.len() == 3 {
-            DynamicImage::ImageRgb8(RgbImage::from_pixel(512, 512, *Rgb::from_slice(&input)))
-        } else if input.len() == 4 {
-            DynamicImage::ImageRgba8(RgbaImage::from_pixel(512, 512, *Rgba::from_slice(&input)))
-        } else {
-            return Err(anyhow::anyhow!("Invalid hex color code."));
-        };
-        let im = image.to_rgba8().to_vec();
-        // TODO: This isn't right... Need to figure out a better way to represent these.
-        return Ok(Image::new(DataSource::Memory(im, input_format)));
+    if is_pattern(input) {
+        return Ok(Image::new(DataSource::Canvas(Pattern::from_str(input)?)));
     }
     Ok(Image::new(DataSource::File(PathBuf::from(input), input_format)))
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn fills_path_placeholders() {
+        let input = PathBuf::from("photos/june/beach.heic");
+        let path = create_path("{dir}/{}-{i}of{page_count}.{ext}.{format}/{filename}", &input, 2, 12);
+        assert_eq!(path, "photos/june/beach-02of12.heic.{format}/beach.heic");
+
+        let date = DateTime::from_system_time(UNIX_EPOCH + Duration::from_secs(1623760496));
+        assert_eq!(date.format("%F %T %y%%"), "2021-06-15 12:34:56 21%");
+        assert_eq!(DateTime::from_system_time(UNIX_EPOCH + Duration::from_secs(951782400)).format("%F"), "2000-02-29");
+        assert_eq!(DateTime::from_system_time(UNIX_EPOCH - Duration::from_secs(1)).format("%F %T"), "1969-12-31 23:59:59");
+
+        let path = fill_image_placeholders("{date:%Y/%m}/{date}_{width}x{height}_{hash}.png", 640, 480, b"", date);
+        assert_eq!(path, "2021/06/2021-06-15_640x480_cbf29ce484222325.png");
+    }
+
+    #[test]
+    fn recognizes_patterns() {
+        assert!(is_pattern("#ff0000") && is_pattern("red") && is_pattern("noise(42)"));
+        assert!(is_pattern("Linear-Gradient (red, nope)"));
+        assert!(!is_pattern("missing (1).jpg") && !is_pattern("#1 photo.png"));
+    }
+
+    #[test]
+    fn replaces_files_keeping_mtime_and_backup() {
+        let dir = std::env::temp_dir().join(format!("imcon-replace-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("a.png");
+        assert!(replace_file(&path, b"new", true).is_err());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+
+        fs::write(&path, b"old").unwrap();
+        let mtime = UNIX_EPOCH + Duration::from_secs(1623760496);
+        let file = File::options().write(true).open(&path).unwrap();
+        file.set_modified(mtime).unwrap();
+        let mut permissions = file.metadata().unwrap().permissions();
+        permissions.set_readonly(true);
+        file.set_permissions(permissions).unwrap();
+
+        replace_file(&path, b"new", true).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"new");
+        assert_eq!(fs::metadata(&path).unwrap().modified().unwrap(), mtime);
+        let backup = dir.join("a.png.bak");
+        assert_eq!(fs::read(&backup).unwrap(), b"old");
+        assert_eq!(fs::metadata(&backup).unwrap().modified().unwrap(), mtime);
+        assert!(fs::metadata(&backup).unwrap().permissions().readonly());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
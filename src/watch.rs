@@ -0,0 +1,99 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::Result;
+use notify::{Config, Event, EventKind, PollWatcher, RecommendedWatcher, RecursiveMode, Watcher};
+use crate::batch::Input;
+
+
+pub struct WatchOptions {
+    pub recursive: bool,
+    /// Poll the directory instead of relying on filesystem notifications.
+    pub poll: bool,
+    pub interval: Duration,
+    /// How long a file must go without changes before it is converted.
+    pub debounce: Duration,
+}
+
+
+fn start<W: Watcher + 'static>(dir: &Path, mode: RecursiveMode, events: Sender<notify::Result<Event>>, config: Config) -> notify::Result<Box<dyn Watcher>> {
+    let mut watcher = W::new(events, config)?;
+    watcher.watch(dir, mode)?;
+    Ok(Box::new(watcher))
+}
+
+
+/// Hidden files include editors' swap files and the temporary files imcon writes before renaming
+/// them into place.
+fn is_hidden(path: &Path) -> bool {
+    path.file_name().is_some_and(|name| name.to_string_lossy().starts_with('.'))
+}
+
+
+/// Watch `dir` and call `convert` on each image added to it or modified, once it has settled, until
+/// interrupted. `convert` returns the files it wrote, which are ignored unless something else
+/// modifies them later, so outputs inside the watched directory aren't converted in turn.
+pub fn watch(dir: &Path, options: &WatchOptions, convert: impl Fn(&Input) -> Result<Vec<PathBuf>>) -> Result<()> {
+    let root = fs::canonicalize(dir)?;
+    let mode = if options.recursive { RecursiveMode::Recursive } else { RecursiveMode::NonRecursive };
+    let config = Config::default().with_poll_interval(options.interval);
+    let (events, received) = channel();
+    let _watcher = if options.poll {
+        start::<PollWatcher>(&root, mode, events, config)?
+    } else {
+        match start::<RecommendedWatcher>(&root, mode, events.clone(), config) {
+            Ok(watcher) => watcher,
+            Err(e) => {
+                eprintln!("Filesystem notifications are unavailable ({}), polling instead.", e);
+                start::<PollWatcher>(&root, mode, events, config)?
+            }
+        }
+    };
+    eprintln!("Watching {}", dir.display());
+
+    let mut pending: HashMap<PathBuf, Instant> = HashMap::new();
+    let mut written: HashMap<PathBuf, SystemTime> = HashMap::new();
+    loop {
+        let timeout = pending.values().min()
+            .map_or(Duration::from_secs(3600), |&changed| (changed + options.debounce).saturating_duration_since(Instant::now()));
+        match received.recv_timeout(timeout) {
+            Ok(Ok(event)) => if matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) {
+                for path in event.paths {
+                    pending.insert(path, Instant::now());
+                }
+            },
+            Ok(Err(e)) => eprintln!("Error watching {}: {}", dir.display(), e),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        }
+
+        let mut settled = pending.iter()
+            .filter(|(_, &changed)| changed.elapsed() >= options.debounce)
+            .map(|(path, _)| path.clone())
+            .collect::<Vec<_>>();
+        settled.sort();
+        for path in settled {
+            pending.remove(&path);
+            let modified = fs::metadata(&path).and_then(|m| m.modified()).ok();
+            if !path.is_file() || is_hidden(&path) || (modified.is_some() && written.get(&path) == modified.as_ref()) {
+                continue;
+            }
+            // Name the input the way the directory was given, so outputs mirror it the same way.
+            let input = Input::new(&dir.join(path.strip_prefix(&root).unwrap_or(&path)), dir);
+            if !input.is_supported() {
+                continue;
+            }
+            match convert(&input) {
+                Ok(outputs) => for output in outputs {
+                    if let (Ok(output), Ok(modified)) = (fs::canonicalize(&output), fs::metadata(&output).and_then(|m| m.modified())) {
+                        written.insert(output, modified);
+                    }
+                },
+                Err(e) => eprintln!("Failed to convert {}: {:?}", input.path, e),
+            }
+        }
+    }
+}